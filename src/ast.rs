@@ -0,0 +1,141 @@
+use crate::error::Span;
+use crate::expr::{Expr, Func, Operation};
+
+/// A parsed expression as a tree, ready to be compiled to stack operations
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ast {
+    pub kind: AstKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AstKind {
+    /// An operation on the values of its arguments, which it takes in order
+    Op(Operation, Vec<Ast>),
+    /// fn name(params) = body
+    Def(String, Vec<String>, Box<Ast>),
+    /// let name = value in body, holding the value then the body
+    Let(String, Box<[Ast; 2]>),
+    /// { statements }, the value of the last
+    Block(Vec<Ast>),
+    /// if cond then a else b, holding the three in order
+    If(Box<[Ast; 3]>),
+    /// repeat count body, holding the count then the body
+    Repeat(Box<[Ast; 2]>),
+}
+
+impl Ast {
+    pub fn op(op: Operation, args: Vec<Ast>, span: Span) -> Self {
+        Self {
+            kind: AstKind::Op(op, args),
+            span,
+        }
+    }
+
+    /// The nodes this one is built from, in source order
+    pub fn args(&self) -> &[Ast] {
+        match &self.kind {
+            AstKind::Op(_, args) => args,
+            AstKind::Def(_, _, body) => std::slice::from_ref(body),
+            AstKind::Let(_, vb) => &vb[..],
+            AstKind::Block(stmts) => stmts,
+            AstKind::If(parts) => &parts[..],
+            AstKind::Repeat(cb) => &cb[..],
+        }
+    }
+
+    /// How many nodes deep the tree goes
+    pub fn depth(&self) -> usize {
+        1 + self.args().iter().map(Ast::depth).max().unwrap_or(0)
+    }
+
+    /// Compile to the flat operation list the Context runs
+    pub fn compile(&self) -> Expr {
+        let mut e = Expr::new();
+        self.compile_into(&mut e);
+        e
+    }
+
+    pub fn compile_into(&self, e: &mut Expr) {
+        match &self.kind {
+            AstKind::Op(op, args) => {
+                for a in args {
+                    a.compile_into(e);
+                }
+                e.push(op.clone(), self.span);
+            }
+            AstKind::Def(name, params, body) => {
+                let f = Func {
+                    name: name.clone(),
+                    params: params.clone(),
+                    body: body.compile(),
+                };
+                e.push(Operation::Def(Box::new(f)), self.span);
+            }
+            AstKind::Let(name, vb) => {
+                let [value, body] = &**vb;
+                value.compile_into(e);
+                e.push(Operation::Let(name.clone()), self.span);
+                body.compile_into(e);
+                e.push(Operation::EndScope, self.span);
+            }
+            AstKind::Block(stmts) => {
+                e.push(Operation::Scope, self.span);
+                for (i, st) in stmts.iter().enumerate() {
+                    st.compile_into(e);
+                    // each statement replaces the value of the one before
+                    if i > 0 {
+                        e.push(Operation::Replace, st.span);
+                    }
+                }
+                e.push(Operation::EndScope, self.span);
+            }
+            AstKind::If(parts) => {
+                let [cond, a, b] = &**parts;
+                cond.compile_into(e);
+                let skip_a = e.push_jump(Operation::JumpUnless(0), self.span);
+                a.compile_into(e);
+                let skip_b = e.push_jump(Operation::Jump(0), self.span);
+                e.patch_jump(skip_a);
+                b.compile_into(e);
+                e.patch_jump(skip_b);
+            }
+            AstKind::Repeat(cb) => {
+                let [count, body] = &**cb;
+                count.compile_into(e);
+                e.push(Operation::Repeat(Box::new(body.compile())), self.span);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod ast_test {
+    use super::*;
+    use crate::parser::parse_ast;
+    use Operation::*;
+
+    #[test]
+    pub fn test_tree() {
+        let a = parse_ast("3d6 + 2 * 4").unwrap();
+        assert!(matches!(a.kind, AstKind::Op(Add, _)));
+        let args = a.args();
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0].args().len(), 2);
+        assert_eq!(args[1].kind, AstKind::Op(Mul, args[1].args().to_vec()));
+        assert_eq!(a.span, Span::new(4, 5));
+        assert_eq!(
+            a.compile().ops,
+            vec![Num(3), Num(6), D, Num(2), Num(4), Mul, Add]
+        );
+    }
+
+    #[test]
+    pub fn test_modifier_args() {
+        let a = parse_ast("4d6!>=5 + [1, 2, 3]").unwrap();
+        let args = a.args();
+        assert_eq!(args[0].args().len(), 3);
+        assert_eq!(args[1].args().len(), 3);
+        assert_eq!(parse_ast("5d10 s >=8 f 1").unwrap().args().len(), 3);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::context::Context;
+use crate::dice::Value;
+use crate::expr::{call_func, check_args};
+use err_tools::*;
+
+/// Functions every program can call, with how many arguments each takes.
+/// Each takes a list first, a single value counting as a list of one.
+/// These names are reserved: a program cannot define a function with any of them
+pub const BUILTINS: &[(&str, usize)] = &[
+    ("map", 2),
+    ("filter", 2),
+    ("fold", 3),
+    ("reduce", 2),
+    ("sort", 1),
+    ("reverse", 1),
+    ("unique", 1),
+    ("max", 1),
+    ("min", 1),
+    ("sum", 1),
+    ("index", 2),
+    ("slice", 3),
+];
+
+/// Call a function value, which names either a function defined in the language or a builtin
+pub fn apply(ct: &mut Context, f: &Value, args: Vec<Value>) -> anyhow::Result<Value> {
+    let name = f.to_string();
+    match ct.get_func(&name) {
+        Some(f) => {
+            call_func(ct, &f, args)?;
+            ct.try_pop()
+        }
+        None => call(ct, &name, args),
+    }
+}
+
+/// Where i falls in a list of len, negative counting back from the end
+fn position(i: i32, len: usize) -> i64 {
+    match i {
+        i if i < 0 => len as i64 + i as i64,
+        i => i as i64,
+    }
+}
+
+pub fn call(ct: &mut Context, name: &str, args: Vec<Value>) -> anyhow::Result<Value> {
+    let want = BUILTINS
+        .iter()
+        .find(|(b, _)| *b == name)
+        .map(|(_, n)| *n)
+        .e_string(format!("No function called '{}'", name))?;
+    check_args(name, want, args.len())?;
+    let mut args = args.into_iter();
+    let mut l = args.next().e_str("Missing argument")?.as_list();
+    let mut arg = || args.next().e_str("Missing argument");
+    let v = match name {
+        "map" => {
+            let f = arg()?;
+            let mut res = Vec::new();
+            for v in l {
+                res.push(apply(ct, &f, vec![v])?);
+            }
+            Value::List(res)
+        }
+        "filter" => {
+            let f = arg()?;
+            let mut res = Vec::new();
+            for v in l {
+                if apply(ct, &f, vec![v.clone()])?.as_bool()? {
+                    res.push(v);
+                }
+            }
+            Value::List(res)
+        }
+        "fold" => {
+            let init = arg()?;
+            let f = arg()?;
+            l.into_iter()
+                .try_fold(init, |acc, v| apply(ct, &f, vec![acc, v]))?
+        }
+        "reduce" => {
+            let f = arg()?;
+            let mut l = l.into_iter();
+            let first = l.next().e_str("Cannot reduce an empty list")?;
+            l.try_fold(first, |acc, v| apply(ct, &f, vec![acc, v]))?
+        }
+        "sort" => {
+            l.sort_by(Value::cmp_total);
+            Value::List(l)
+        }
+        "reverse" => {
+            l.reverse();
+            Value::List(l)
+        }
+        "unique" => {
+            let mut res: Vec<Value> = Vec::new();
+            for v in l {
+                if !res.contains(&v) {
+                    res.push(v);
+                }
+            }
+            Value::List(res)
+        }
+        "max" => l
+            .into_iter()
+            .max_by(Value::cmp_total)
+            .e_str("Cannot take the max of an empty list")?,
+        "min" => l
+            .into_iter()
+            .min_by(Value::cmp_total)
+            .e_str("Cannot take the min of an empty list")?,
+        "sum" => Value::Num(Value::List(l).as_int()?),
+        "index" => {
+            let i = arg()?.as_int()?;
+            let p = position(i, l.len());
+            if p < 0 || p >= l.len() as i64 {
+                return e_string(format!(
+                    "Index {} is out of range for a list of {}",
+                    i,
+                    l.len()
+                ));
+            }
+            l.swap_remove(p as usize)
+        }
+        "slice" => {
+            let clamp = |i: i32| position(i, l.len()).clamp(0, l.len() as i64) as usize;
+            let a = clamp(arg()?.as_int()?);
+            let b = clamp(arg()?.as_int()?).max(a);
+            Value::List(l[a..b].to_vec())
+        }
+        _ => return e_string(format!("No function called '{}'", name)),
+    };
+    Ok(v)
+}
+
+#[cfg(test)]
+mod builtin_test {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn run(s: &str) -> anyhow::Result<String> {
+        let mut ct = Context::new();
+        let mut res = Value::Num(0);
+        for e in parse_program(s)? {
+            res = e.resolve(&mut ct)?;
+        }
+        Ok(res.to_string())
+    }
+
+    #[test]
+    pub fn test_builtins() {
+        let ok = |s: &str| run(s).unwrap();
+        assert_eq!(ok("map([1, 2, 3], fn twice(x) = $x * 2)"), "[2, 4, 6]");
+        assert_eq!(ok("filter([1, 5, 2, 6], fn big(x) = $x gt 3)"), "[5, 6]");
+        assert_eq!(ok("fn add(a, b) = $a + $b; fold([1, 2, 3], 10, add)"), "16");
+        assert_eq!(ok("fn add(a, b) = $a + $b; reduce([1, 2, 3], add)"), "6");
+        assert_eq!(ok("sort([3, [1, 1], 1, \"a\"])"), "[1, [1, 1], 3, a]");
+        assert_eq!(ok("reverse([1, 2, 3])"), "[3, 2, 1]");
+        assert_eq!(ok("unique([1, 2, 1, 3, 2])"), "[1, 2, 3]");
+        assert_eq!(ok("max([2, 5, 1]) + min([2, 5, 1])"), "6");
+        assert_eq!(ok("sum(4d[2])"), "8");
+        assert_eq!(ok("index([4, 5, 6], 0) + index([4, 5, 6], -1)"), "10");
+        assert_eq!(ok("slice([1, 2, 3, 4], 1, -1)"), "[2, 3]");
+        assert_eq!(ok("slice([1, 2, 3, 4], 3, 1)"), "[]");
+        assert_eq!(ok("map(repeat 2 3d[2], sum)"), "[6, 6]");
+        assert_eq!(ok("sum(5)"), "5");
+        assert_eq!(ok("max(6)"), "6");
+
+        let err = |s: &str| run(s).unwrap_err().to_string();
+        assert_eq!(
+            err("index([1], 1)"),
+            "Index 1 is out of range for a list of 1"
+        );
+        assert_eq!(err("reduce([], max)"), "Cannot reduce an empty list");
+        assert_eq!(err("map([1], nope)"), "No function called 'nope'");
+        assert_eq!(
+            err("fn sum(xs) = 1; sum([3, 2])"),
+            "Cannot redefine the builtin 'sum'"
+        );
+        assert_eq!(
+            err("fn sort(xs) = 1; sort([3, 2])"),
+            "Cannot redefine the builtin 'sort'"
+        );
+        assert_eq!(
+            err("sum([1], 2)"),
+            "sum takes 1 argument but was given 2 arguments"
+        );
+        assert_eq!(
+            err("sort([1], 2)"),
+            "sort takes 1 argument but was given 2 arguments"
+        );
+    }
+}
@@ -1,62 +1,245 @@
-use crate::dice::Value;
+use crate::dice::{Successes, Value};
+use crate::explain::{DieMark, Explainer, Node};
+use crate::expr::{Func, Operation};
+use crate::limits::{Limit, Limits};
 use err_tools::*;
-use rand::rngs::ThreadRng;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::fmt::{self, Display};
+use std::rc::Rc;
+
+/// The outcome of a roll as written by --json, one object per line:
+///
+/// {"expr": "3d6 as a", "result": value, "rolls": [value], "stack": [value], "vars": {"a": value}}
+///
+/// where each value is tagged by its type:
+/// {"type": "num", "value": 3}, {"type": "word", "value": "a"}, {"type": "range", "value": [1, 6]},
+/// {"type": "list", "value": [value]}, {"type": "bool", "value": true}
+/// or {"type": "successes", "value": {"hits": 2, "fails": 0, "botch": false}}
+///
+/// "rolls" holds only the dice rolled by that expression, not earlier ones.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub expr: &'a str,
+    pub result: &'a Value,
+    pub rolls: &'a [Value],
+    pub stack: &'a [Value],
+    pub vars: BTreeMap<&'a String, &'a Value>,
+}
+
+/// Variables bound by a let or block, or the locals of a function call
+#[derive(Debug, Default)]
+struct Scope {
+    vars: BTreeMap<String, Value>,
+    call: bool, //Lookups stop here, so a body cannot see its caller's scopes
+}
 
-#[derive(Debug)]
 pub struct Context {
     stack: Vec<Value>,
     rolls: Vec<Value>,
+    successes: Vec<Successes>,
     vars: BTreeMap<String, Value>,
-    rng: ThreadRng,
+    scopes: Vec<Scope>, //Innermost last, the globals are outside them all
+    funcs: BTreeMap<String, Rc<Func>>,
+    rng: Box<dyn RngCore>,
+    explain: Option<Explainer>,
+    limits: Limits,
+    dice: usize, //Rolled this expression
+    steps: usize,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    /// A Context whose rolls are reproducible from the seed
+    pub fn seeded(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    /// A Context rolling with any source of randomness
+    pub fn with_rng<R: RngCore + 'static>(rng: R) -> Self {
         Self {
             stack: Vec::new(),
             rolls: Vec::new(),
+            successes: Vec::new(),
             vars: BTreeMap::new(),
-            rng: rand::thread_rng(),
+            scopes: Vec::new(),
+            funcs: BTreeMap::new(),
+            rng: Box::new(rng),
+            explain: None,
+            limits: Limits::default(),
+            dice: 0,
+            steps: 0,
         }
     }
 
+    /// Forget everything but the rng, as if newly created
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.rolls.clear();
+        self.successes.clear();
+        self.vars.clear();
+        self.scopes.clear();
+        self.funcs.clear();
+        self.explain = None;
+    }
+
+    /// Make all following rolls reproducible from the seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = Box::new(StdRng::seed_from_u64(seed));
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Start counting dice and steps afresh for a new expression
+    pub fn reset_counts(&mut self) {
+        self.dice = 0;
+        self.steps = 0;
+    }
+
+    /// Count an evaluation step, erroring if it takes too long
+    pub fn step(&mut self) -> anyhow::Result<()> {
+        self.steps += 1;
+        self.limits.check(Limit::Steps, self.steps)
+    }
+
+    /// Error if the stack has grown too deep, checked once an op has pushed its result
+    pub fn check_stack(&self) -> anyhow::Result<()> {
+        self.limits.check(Limit::StackDepth, self.stack.len())
+    }
+
+    /// Count dice about to be rolled together
+    pub fn count_dice(&mut self, n: i32) -> anyhow::Result<()> {
+        let n = n.max(0) as usize;
+        self.limits.check(Limit::DicePerRoll, n)?;
+        self.dice += n;
+        self.limits.check(Limit::DiceTotal, self.dice)
+    }
+
     pub fn prev(&self) -> Option<Value> {
-        self.rolls.last().map(|c| c.clone())
+        self.rolls.last().cloned()
     }
 
     pub fn push(&mut self, dr: Value) {
+        if let Some(ex) = &mut self.explain {
+            ex.push(&dr);
+        }
         self.stack.push(dr);
     }
 
     /// Push Var Value onto run stack
     pub fn var(&mut self, name: &str) -> anyhow::Result<()> {
-        let v = self.vars.get(name).e_str("Could not get var")?.clone();
-        self.stack.push(v);
+        let v = self
+            .get_var(name)
+            .e_string(format!("Could not get var '{}'", name))?;
+        self.push(v);
         Ok(())
     }
 
-    //Clone Stack top onto Var list
+    //Clone Stack top onto Var list, in the innermost scope if there is one
     pub fn push_var(&mut self, name: String) -> anyhow::Result<()> {
         let v = self.try_top()?.clone();
-        self.vars.insert(name, v);
+        match self.scopes.last_mut() {
+            Some(sc) => sc.vars.insert(name, v),
+            None => self.vars.insert(name, v),
+        };
+        Ok(())
+    }
+
+    pub fn define(&mut self, f: Func) {
+        self.funcs.insert(f.name.clone(), Rc::new(f));
+    }
+
+    pub fn get_func(&self, name: &str) -> Option<Rc<Func>> {
+        self.funcs.get(name).cloned()
+    }
+
+    pub fn funcs(&self) -> &BTreeMap<String, Rc<Func>> {
+        &self.funcs
+    }
+
+    /// Enter a function call, whose body sees only these locals and the globals
+    pub fn push_frame(&mut self, locals: BTreeMap<String, Value>) -> anyhow::Result<()> {
+        let calls = self.scopes.iter().filter(|sc| sc.call).count();
+        self.limits.check(Limit::CallDepth, calls + 1)?;
+        self.scopes.push(Scope {
+            vars: locals,
+            call: true,
+        });
         Ok(())
     }
 
+    /// Leave the innermost function call, along with any scopes left open inside it
+    pub fn pop_frame(&mut self) {
+        let l = self.scopes.iter().rposition(|sc| sc.call).unwrap_or(0);
+        self.scopes.truncate(l);
+    }
+
+    /// Enter a scope whose bindings hide outer ones until it is popped
+    pub fn push_scope(&mut self, vars: BTreeMap<String, Value>) {
+        self.scopes.push(Scope { vars, call: false });
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    pub fn scope_depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// Close any scopes opened past the depth, as when an expression errors inside them
+    pub fn truncate_scopes(&mut self, depth: usize) {
+        self.scopes.truncate(depth);
+    }
+
+    /// Drop anything pushed since the stack was len long
+    pub fn truncate_stack(&mut self, len: usize) {
+        self.stack.truncate(len);
+    }
+
     pub fn pop(&mut self) -> Option<Value> {
+        if let Some(ex) = &mut self.explain {
+            ex.pop();
+        }
         self.stack.pop()
     }
 
     pub fn try_pop(&mut self) -> anyhow::Result<Value> {
-        self.stack.pop().e_str("Nothing on Stack")
+        self.pop().e_str("Nothing on Stack")
     }
 
     pub fn try_top(&mut self) -> anyhow::Result<Value> {
-        self.stack
-            .last()
-            .e_str("Nothing on Stack")
-            .map(Value::clone)
+        self.stack.last().cloned().e_str("Nothing on Stack")
+    }
+
+    /// Pop a value but leave its explanation, for ops that collect values as
+    /// they go and take their explanations together with `explain_inputs`
+    pub fn pop_value(&mut self) -> anyhow::Result<Value> {
+        self.stack.pop().e_str("Nothing on Stack")
+    }
+
+    /// Take the explanations of the last n values as inputs to the current op
+    pub fn explain_inputs(&mut self, n: usize) {
+        if let Some(ex) = &mut self.explain {
+            ex.pop_n(n);
+        }
     }
 
     pub fn top_n(&mut self, n: usize) -> anyhow::Result<Vec<Value>> {
@@ -64,52 +247,180 @@ impl Context {
         if n > l {
             return e_str("Cannot take that many elements");
         }
+        if let Some(ex) = &mut self.explain {
+            ex.pop_n(n);
+        }
         Ok(self.stack.split_off(l - n))
     }
 
     pub fn push_roll(&mut self, dr: Value) {
-        self.stack.push(dr.clone());
+        self.push(dr.clone());
+        self.rolls.push(dr);
+    }
+
+    /// Record a roll in the log without putting it on the stack
+    pub fn log_roll(&mut self, dr: Value) {
         self.rolls.push(dr);
     }
 
+    pub fn log_successes(&mut self, s: Successes) {
+        self.successes.push(s);
+    }
+
     pub fn last_roll(&self) -> Option<Value> {
-        self.rolls.last().map(Value::clone)
+        self.rolls.last().cloned()
     }
 
-    pub fn get_var(&mut self, s: &str) -> Option<Value> {
-        self.vars.get(s).map(|v| v.clone())
+    /// Look a variable up from the innermost scope outward,
+    /// skipping from a function call straight to the globals
+    pub fn get_var(&self, s: &str) -> Option<Value> {
+        for sc in self.scopes.iter().rev() {
+            if let Some(v) = sc.vars.get(s) {
+                return Some(v.clone());
+            }
+            if sc.call {
+                break;
+            }
+        }
+        self.vars.get(s).cloned()
     }
 
-    pub fn rng(&mut self) -> &mut ThreadRng {
-        &mut self.rng
+    /// The global variables
+    pub fn vars(&self) -> &BTreeMap<String, Value> {
+        &self.vars
+    }
+
+    pub fn rolls(&self) -> &[Value] {
+        &self.rolls
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Variables not hidden by a leading '_'
+    pub fn public_vars(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.vars.iter().filter(|(k, _)| !k.starts_with('_'))
+    }
+
+    /// Everything a roll left behind, ready to serialize
+    /// Report an expression whose rolls began once there were `first` rolls
+    pub fn report<'a>(&'a self, first: usize, expr: &'a str, result: &'a Value) -> Report<'a> {
+        Report {
+            expr,
+            result,
+            rolls: &self.rolls[first.min(self.rolls.len())..],
+            stack: &self.stack,
+            vars: self.public_vars().collect(),
+        }
+    }
+
+    /// Start building an explanation tree as operations resolve
+    pub fn explain(&mut self) {
+        self.explain.get_or_insert_with(Explainer::default);
+    }
+
+    pub fn begin_op(&mut self, op: &Operation) {
+        if let Some(ex) = &mut self.explain {
+            ex.begin(op);
+        }
+    }
+
+    pub fn end_op(&mut self) {
+        if let Some(ex) = &mut self.explain {
+            ex.end();
+        }
+    }
+
+    /// Note what happened to a die for the explanation of the current operation
+    pub fn mark_die(&mut self, v: &Value, m: DieMark) {
+        if let Some(ex) = &mut self.explain {
+            ex.mark(v.clone(), m);
+        }
+    }
+
+    pub fn mark_dice(&mut self, dr: &Value, m: DieMark) {
+        if let Some(ex) = &mut self.explain {
+            for v in dr.clone().as_list() {
+                ex.mark(v, m);
+            }
+        }
+    }
+
+    pub fn mark_kept(&mut self, pool: &Value, kept: &Value) {
+        if let Some(ex) = &mut self.explain {
+            ex.mark_kept(pool, kept);
+        }
+    }
+
+    /// The explanation of the value most recently popped off the stack
+    pub fn take_explanation(&mut self) -> Option<Node> {
+        self.explain.as_mut()?.take_popped()
+    }
+
+    pub fn rng(&mut self) -> &mut dyn RngCore {
+        &mut *self.rng
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("stack", &self.stack)
+            .field("rolls", &self.rolls)
+            .field("successes", &self.successes)
+            .field("vars", &self.vars)
+            .finish_non_exhaustive()
     }
 }
 
 impl Display for Context {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Rolls : ")?;
-        let mut comma = "";
-        for r in &self.rolls {
-            write!(f, "{}{}", comma, r)?;
-            comma = "_ ";
+        for s in &self.successes {
+            writeln!(
+                f,
+                "Successes : {}, Failures : {}, Botch : {}",
+                s.hits, s.fails, s.botch
+            )?;
         }
 
-        if self.stack.len() > 0 {
-            write!(f, "\nStack : ")?;
+        if !self.stack.is_empty() {
+            write!(f, "Stack : ")?;
             let mut comma = "";
             for r in &self.stack {
                 write!(f, "{}{}", comma, r)?;
                 comma = "_ ";
             }
+            writeln!(f)?;
         }
-        write!(f, "\n")?;
 
-        for (k, v) in &self.vars {
-            if !k.starts_with("_") {
-                writeln!(f, "{} {}", k, v)?;
-            }
+        for (k, v) in self.public_vars() {
+            writeln!(f, "{} {}", k, v)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod context_test {
+    use super::*;
+    use crate::parser::parse_expr;
+
+    #[test]
+    pub fn test_report() {
+        let mut ct = Context::new();
+        let e = parse_expr("(2d[3] as _x) + 1 as y").unwrap();
+        let v = e.resolve(&mut ct).unwrap();
+        let js = serde_json::to_string(&ct.report(0, "x", &v)).unwrap();
+        assert_eq!(
+            js,
+            r#"{"expr":"x","result":{"type":"num","value":7},"rolls":[{"type":"list","value":[{"type":"num","value":3},{"type":"num","value":3}]}],"stack":[],"vars":{"y":{"type":"num","value":7}}}"#
+        );
+        let first = ct.rolls().len();
+        let v = parse_expr("$y + 1").unwrap().resolve(&mut ct).unwrap();
+        assert!(ct.report(first, "$y + 1", &v).rolls.is_empty());
+        let back: Value = serde_json::from_str(r#"{"type":"range","value":[1,6]}"#).unwrap();
+        assert_eq!(back, Value::Range(1, 6));
+    }
+}
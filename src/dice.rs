@@ -1,26 +1,193 @@
+use crate::error::{DiceError, ErrorKind};
 use err_tools::*;
 use rand::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::{Ordering, PartialOrd};
 use std::fmt::{self, Display};
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// A comparison between a rolled die and a target, used for filters and dice modifiers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEq,
+    GreaterEq,
+}
+
+impl Compare {
+    pub fn test(&self, a: &Value, b: &Value) -> bool {
+        match self {
+            Self::Equal => a == b,
+            Self::NotEqual => a != b,
+            Self::Less => a < b,
+            Self::Greater => a > b,
+            Self::LessEq => a <= b,
+            Self::GreaterEq => a >= b,
+        }
+    }
+}
+
+/// How division rounds results that are not whole
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Round {
+    Down,
+    Up,
+    Nearest,
+}
+
+impl Round {
+    pub fn div(&self, a: i32, b: i32) -> anyhow::Result<i32> {
+        // i64 leaves room to negate and double any i32
+        let (a, b) = match b as i64 {
+            0 => return e_str("Division by zero"),
+            b if b < 0 => (-(a as i64), -b),
+            b => (a as i64, b),
+        };
+        let res = match self {
+            Self::Down => a.div_euclid(b),
+            Self::Up => -(-a).div_euclid(b),
+            Self::Nearest => (2 * a + b).div_euclid(2 * b),
+        };
+        checked(i32::try_from(res).ok())
+    }
+}
+
+/// An overflow error if the arithmetic did not fit
+pub fn checked(n: Option<i32>) -> anyhow::Result<i32> {
+    n.ok_or_else(|| DiceError::new(ErrorKind::Overflow, None).into())
+}
+
+pub fn add(a: i32, b: i32) -> anyhow::Result<i32> {
+    checked(a.checked_add(b))
+}
+
+pub fn sub(a: i32, b: i32) -> anyhow::Result<i32> {
+    checked(a.checked_sub(b))
+}
+
+pub fn mul(a: i32, b: i32) -> anyhow::Result<i32> {
+    checked(a.checked_mul(b))
+}
+
+pub fn neg(a: i32) -> anyhow::Result<i32> {
+    checked(a.checked_neg())
+}
+
+/// Remainder that is never negative, for wrapping around tables
+pub fn modulo(a: i32, b: i32) -> anyhow::Result<i32> {
+    if b == 0 {
+        return e_str("Modulo by zero");
+    }
+    checked(a.checked_rem_euclid(b))
+}
+
+/// The outcome of counting successes in a dice pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Successes {
+    pub hits: i32,
+    pub fails: i32,
+    pub botch: bool,
+}
+
+impl Successes {
+    /// Count the dice in a pool meeting the hit target, counting those that also
+    /// meet the double target twice, and the dice missing the hit but meeting the fail target.
+    /// A pool with failures and no hits is a botch.
+    pub fn count(
+        pool: &Value,
+        hit: (Compare, Value),
+        double: Option<(Compare, Value)>,
+        fail: Option<(Compare, Value)>,
+    ) -> Self {
+        let mut hits = 0;
+        let mut fails = 0;
+        for d in pool.clone().as_list() {
+            if hit.0.test(&d, &hit.1) {
+                hits += match &double {
+                    Some((c, t)) if c.test(&d, t) => 2,
+                    _ => 1,
+                };
+            } else if let Some((c, t)) = &fail {
+                if c.test(&d, t) {
+                    fails += 1;
+                }
+            }
+        }
+        Successes {
+            hits,
+            fails,
+            botch: hits == 0 && fails > 0,
+        }
+    }
+
+    pub fn net(&self) -> i32 {
+        self.hits - self.fails
+    }
+}
+
+impl Display for Successes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} successes, {} failures", self.hits, self.fails)?;
+        if self.botch {
+            write!(f, ", botch")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serialized tagged by type, eg {"type": "num", "value": 3}
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
 pub enum Value {
     Num(i32),
     Word(String),
     Range(i32, i32),
     List(Vec<Value>),
+    Successes(Successes),
+    Bool(bool),
 }
 
 impl Value {
+    /// The value as a number, true counting as 1 and false as 0
     pub fn as_int(&self) -> anyhow::Result<i32> {
         match self {
             Self::Word(_) => e_str("Cannot use String as Number"),
             Self::Num(n) => Ok(*n),
-            Self::List(l) => l.iter().try_fold(0, |f, v| Ok(f + v.as_int()?)),
+            Self::Bool(b) => Ok(*b as i32),
+            Self::Successes(s) => Ok(s.net()),
+            Self::List(l) => l.iter().try_fold(0, |f, v| add(f, v.as_int()?)),
             Self::Range(_, _) => e_str("Cannot use Range as Number"),
         }
     }
 
+    pub fn as_bool(&self) -> anyhow::Result<bool> {
+        match self {
+            Self::Bool(b) => Ok(*b),
+            v => e_string(format!("Expected true or false, found {}", v)),
+        }
+    }
+
+    /// Compare as numbers when both sides are, so a rolled pool compares by its total
+    pub fn test(&self, c: Compare, b: &Value) -> bool {
+        match (self.as_int(), b.as_int()) {
+            (Ok(a), Ok(b)) => c.test(&Value::Num(a), &Value::Num(b)),
+            _ => c.test(self, b),
+        }
+    }
+
+    /// Order by total where both have one, so rolled pools sort by their sum,
+    /// putting values with no total last
+    pub fn cmp_total(&self, b: &Value) -> Ordering {
+        match (self.as_int(), b.as_int()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            _ => self.cmp(b),
+        }
+    }
+
     pub fn count(&self) -> Value {
         match self {
             Self::List(l) => Value::Num(l.len() as i32),
@@ -38,19 +205,19 @@ impl Value {
     pub fn lowest_n(self, n: usize) -> Value {
         let mut v = self.as_list();
         v.sort();
-        if n as usize <= v.len() {
+        if n <= v.len() {
             v.drain(n..);
         }
-        return Value::List(v);
+        Value::List(v)
     }
     pub fn highest_n(self, n: usize) -> Value {
         let mut v = self.as_list();
         v.sort();
-        if n as usize <= v.len() {
+        if n <= v.len() {
             let n = v.len() - n;
             v.drain(..n);
         }
-        return Value::List(v);
+        Value::List(v)
     }
 
     pub fn append(self, b: Self) -> Self {
@@ -63,6 +230,8 @@ impl Value {
         match self {
             Self::Word(_) => e_str("Words are not High or Low"),
             Self::Num(n) => Ok(*n),
+            Self::Bool(b) => Ok(*b as i32),
+            Self::Successes(s) => Ok(s.net()),
             Self::Range(a, b) => Ok((*a).max(*b)),
             Self::List(l) => {
                 let mut res = None;
@@ -95,6 +264,29 @@ impl Value {
         self._most(i32::min).map(Value::Num)
     }
 
+    /// Errors if this die has no faces to roll
+    pub fn check_die(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Num(n) if *n < 1 => e_string(format!("Cannot roll a d{}", n)),
+            Self::Range(a, b) if a == b => {
+                e_string(format!("Cannot roll the empty range {}", self))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The highest value a single roll of this die can produce
+    pub fn max_face(&self) -> anyhow::Result<Value> {
+        match self {
+            Self::Num(n) => Ok(Value::Num(*n)),
+            Self::Range(a, b) => Ok(Value::Num((*a).max(*b) - 1)),
+            Self::Word(_) => e_str("Words have no faces"),
+            Self::Successes(_) => e_str("Successes have no faces"),
+            Self::Bool(_) => e_str("Bools have no faces"),
+            Self::List(_) => self.highest(),
+        }
+    }
+
     pub fn flatten(&self) -> anyhow::Result<Vec<Value>> {
         match self {
             Self::List(l) => {
@@ -108,29 +300,37 @@ impl Value {
         }
     }
 
-    pub fn roll_n(&self, n: i32) -> Value {
-        let mut tr = rand::thread_rng();
+    /// The die numbered from 0 rather than 1, as a range
+    pub fn zero_based(&self) -> anyhow::Result<Value> {
+        match self {
+            Self::Num(n) if *n > 0 => Ok(Value::Range(0, *n)),
+            v => e_string(format!("Cannot number a d{} from 0", v)),
+        }
+    }
+
+    pub fn roll_n<R: Rng + ?Sized>(&self, n: i32, r: &mut R) -> Value {
         match n {
-            1 => self.roll(&mut tr),
+            1 => self.roll(r),
             v => {
                 let mut res = Vec::new();
                 for _ in 0..v {
-                    res.push(self.roll(&mut tr));
+                    res.push(self.roll(r));
                 }
                 Value::List(res)
             }
         }
     }
 
-    pub fn roll<R: Rng>(&self, r: &mut R) -> Value {
+    pub fn roll<R: Rng + ?Sized>(&self, r: &mut R) -> Value {
         match self {
-            Self::Num(10) => Value::Num(r.gen_range(0..10)),
             Self::Num(n) => Value::Num(r.gen_range(0..*n) + 1),
             Self::Range(a, b) if a < b => Value::Num(r.gen_range(*a..*b)),
             Self::Range(b, a) => Value::Num(r.gen_range(*a..*b)),
             Self::Word(s) => Value::Word(s.clone()),
+            Self::Successes(s) => Value::Successes(*s),
+            Self::Bool(b) => Value::Bool(*b),
             Self::List(v) => {
-                if v.len() == 0 {
+                if v.is_empty() {
                     return Value::Num(0);
                 }
                 let n = r.gen_range(0..v.len());
@@ -140,20 +340,40 @@ impl Value {
     }
 }
 
+/// Roll a tens and a units die, each 0 to 9, reading 00 as 100.
+/// Returns the total and the two dice
+pub fn roll_percentile<R: Rng + ?Sized>(r: &mut R) -> (i32, i32, i32) {
+    let tens = r.gen_range(0..10) * 10;
+    let units = r.gen_range(0..10);
+    match tens + units {
+        0 => (100, tens, units),
+        n => (n, tens, units),
+    }
+}
+
 impl Ord for Value {
     fn cmp(&self, other: &Self) -> Ordering {
         use Value::*;
         match (self, other) {
             (Num(a), Num(b)) => a.cmp(b),
             (Word(a), Word(b)) => a.cmp(b),
-            (Range(al, ah), Range(bl, bh)) => (2 * ah - al).cmp(&(2 * bh - bl)),
+            (Range(al, ah), Range(bl, bh)) => {
+                let mid = |l: i32, h: i32| 2 * h as i64 - l as i64;
+                mid(*al, *ah).cmp(&mid(*bl, *bh))
+            }
             (List(a), List(b)) => a.cmp(b),
+            (Successes(a), Successes(b)) => a.cmp(b),
+            (Bool(a), Bool(b)) => a.cmp(b),
             (Num(_), _) => Ordering::Less,
             (_, Num(_)) => Ordering::Greater,
             (Word(_), _) => Ordering::Less,
             (_, Word(_)) => Ordering::Greater,
             (Range(_, _), _) => Ordering::Less,
             (_, Range(_, _)) => Ordering::Greater,
+            (List(_), _) => Ordering::Less,
+            (_, List(_)) => Ordering::Greater,
+            (Successes(_), _) => Ordering::Less,
+            (_, Successes(_)) => Ordering::Greater,
         }
     }
 }
@@ -170,8 +390,11 @@ impl Display for Value {
             Self::Word(s) => write!(f, "{}", s)?,
             Self::Num(n) => write!(f, "{}", n)?,
             Self::Range(b, t) => write!(f, "{}..{}", b, t)?,
+            Self::Successes(s) => write!(f, "{}", s)?,
+            Self::Bool(b) => write!(f, "{}", b)?,
             Self::List(l) => {
-                let mut comma = "[";
+                write!(f, "[")?;
+                let mut comma = "";
                 for i in l {
                     write!(f, "{}{}", comma, i)?;
                     comma = ", ";
@@ -182,3 +405,16 @@ impl Display for Value {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod dice_test {
+    use super::*;
+
+    #[test]
+    pub fn test_display() {
+        assert_eq!(Value::List(vec![]).to_string(), "[]");
+        let l = Value::List(vec![Value::Num(1), Value::Range(2, 3)]);
+        assert_eq!(l.to_string(), "[1, 2..3]");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+    }
+}
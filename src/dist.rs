@@ -0,0 +1,350 @@
+use crate::dice::{self, modulo, Value};
+use crate::expr::{Expr, Operation};
+use err_tools::*;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// How many distinct outcomes a distribution may hold before giving up
+pub const MAX_OUTCOMES: usize = 100_000;
+
+/// The most dice one roll can have, as each pool builds on the last
+pub const MAX_DICE: i32 = 100;
+
+/// A bound on the work of building the pools of one roll, see `work`
+pub const MAX_WORK: f64 = 20_000_000.;
+
+/// An exact probability distribution over the values an expression can produce
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dist {
+    outcomes: BTreeMap<Value, f64>,
+}
+
+impl Dist {
+    pub fn constant(v: Value) -> Self {
+        let mut outcomes = BTreeMap::new();
+        outcomes.insert(v, 1.);
+        Self { outcomes }
+    }
+
+    /// Every face a single roll of the die can show, each equally likely
+    pub fn faces(d: &Value) -> anyhow::Result<Self> {
+        let count = match d {
+            Value::Num(n) => *n as i64,
+            Value::Range(a, b) => (*a as i64 - *b as i64).abs(),
+            _ => 0,
+        };
+        if count > MAX_OUTCOMES as i64 {
+            return e_str("Too many outcomes to compute exactly");
+        }
+        let faces: Vec<Value> = match d {
+            Value::Num(n) if *n > 0 => (1..=*n).map(Value::Num).collect(),
+            Value::Range(a, b) if a != b => ((*a).min(*b)..(*a).max(*b)).map(Value::Num).collect(),
+            Value::Word(_) => vec![d.clone()],
+            Value::List(l) if l.is_empty() => vec![Value::Num(0)],
+            Value::List(l) => l.clone(),
+            _ => return e_string(format!("Cannot roll {}", d)),
+        };
+        let p = 1. / faces.len() as f64;
+        let mut res = Self {
+            outcomes: BTreeMap::new(),
+        };
+        for f in faces {
+            res.add(f, p);
+        }
+        Ok(res)
+    }
+
+    fn add(&mut self, v: Value, p: f64) {
+        *self.outcomes.entry(v).or_insert(0.) += p;
+    }
+
+    fn checked(self) -> anyhow::Result<Self> {
+        if self.outcomes.len() > MAX_OUTCOMES {
+            return e_str("Too many outcomes to compute exactly");
+        }
+        Ok(self)
+    }
+
+    pub fn map<F: Fn(&Value) -> anyhow::Result<Value>>(&self, f: F) -> anyhow::Result<Self> {
+        let mut res = Self {
+            outcomes: BTreeMap::new(),
+        };
+        for (v, p) in &self.outcomes {
+            res.add(f(v)?, *p);
+        }
+        Ok(res)
+    }
+
+    /// Combine every pair of outcomes, treating the two distributions as independent
+    pub fn combine<F: Fn(&Value, &Value) -> anyhow::Result<Value>>(
+        &self,
+        b: &Self,
+        f: F,
+    ) -> anyhow::Result<Self> {
+        let mut res = Self {
+            outcomes: BTreeMap::new(),
+        };
+        for (av, ap) in &self.outcomes {
+            for (bv, bp) in &b.outcomes {
+                res.add(f(av, bv)?, ap * bp);
+            }
+        }
+        res.checked()
+    }
+
+    /// The distribution of rolling n of each die, pools are kept sorted
+    /// so that equal pools share an outcome
+    pub fn roll_n(&self, n: &Self) -> anyhow::Result<Self> {
+        let mut res = Self {
+            outcomes: BTreeMap::new(),
+        };
+        for (d, dp) in &self.outcomes {
+            let faces = Self::faces(d)?;
+            for (n, np) in &n.outcomes {
+                let pool = match n.as_int()? {
+                    1 => faces.clone(),
+                    n if n > MAX_DICE || work(n, faces.outcomes.len()) > MAX_WORK => {
+                        return e_str("Too many outcomes to compute exactly")
+                    }
+                    n => {
+                        let mut pool = Self::constant(Value::List(Vec::new()));
+                        for _ in 0..n {
+                            pool = pool.combine(&faces, |a, b| {
+                                let mut l = a.clone().append(b.clone()).as_list();
+                                l.sort();
+                                Ok(Value::List(l))
+                            })?;
+                        }
+                        pool
+                    }
+                };
+                for (v, p) in pool.outcomes {
+                    res.add(v, p * dp * np);
+                }
+            }
+        }
+        res.checked()
+    }
+
+    pub fn outcomes(&self) -> &BTreeMap<Value, f64> {
+        &self.outcomes
+    }
+
+    /// The probability of each numeric result
+    pub fn numbers(&self) -> anyhow::Result<BTreeMap<i32, f64>> {
+        let mut res = BTreeMap::new();
+        for (v, p) in &self.outcomes {
+            *res.entry(v.as_int()?).or_insert(0.) += p;
+        }
+        Ok(res)
+    }
+
+    pub fn mean(&self) -> anyhow::Result<f64> {
+        let n = self.numbers()?;
+        Ok(n.iter().map(|(v, p)| *v as f64 * p).sum())
+    }
+
+    pub fn variance(&self) -> anyhow::Result<f64> {
+        let mean = self.mean()?;
+        let n = self.numbers()?;
+        Ok(n.iter().map(|(v, p)| (*v as f64 - mean).powi(2) * p).sum())
+    }
+
+    pub fn min(&self) -> anyhow::Result<i32> {
+        let n = self.numbers()?;
+        n.keys().next().copied().e_str("Empty distribution")
+    }
+
+    pub fn max(&self) -> anyhow::Result<i32> {
+        let n = self.numbers()?;
+        n.keys().last().copied().e_str("Empty distribution")
+    }
+
+    /// P(X >= n)
+    pub fn at_least(&self, n: i32) -> anyhow::Result<f64> {
+        Ok(self.numbers()?.range(n..).map(|(_, p)| p).sum())
+    }
+}
+
+/// How many different sorted pools n dice with f faces can roll
+fn pools(n: i32, f: usize) -> f64 {
+    (1..f).map(|k| (n as f64 + k as f64) / k as f64).product()
+}
+
+/// Roughly the work of rolling n dice with f faces, as each die pairs every
+/// pool of the dice before it with every face, copying the pool each time
+fn work(n: i32, f: usize) -> f64 {
+    (1..=n).map(|k| pools(k, f) * (f * k as usize) as f64).sum()
+}
+
+impl Display for Dist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (v, p) in &self.outcomes {
+            writeln!(f, "{} : {:.4}%", v, p * 100.)?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! dist2 {
+    ($st:ident,$a:ident,$b:ident,$e:expr) => {{
+        let b = $st.pop().e_str("Nothing on Stack")?;
+        let a = $st.pop().e_str("Nothing on Stack")?;
+        $st.push(a.combine(&b, |$a, $b| Ok($e))?);
+    }};
+}
+
+impl Operation {
+    /// Apply the operation to a stack of distributions rather than values
+    pub fn dist(&self, st: &mut Vec<Dist>) -> anyhow::Result<()> {
+        match self {
+            Self::Num(n) => st.push(Dist::constant(Value::Num(*n))),
+            Self::Word(s) => st.push(Dist::constant(Value::Word(s.clone()))),
+            Self::Bool(b) => st.push(Dist::constant(Value::Bool(*b))),
+            Self::Fudge => st.push(Dist::constant(Value::List(vec![
+                Value::Num(-1),
+                Value::Num(0),
+                Value::Num(1),
+            ]))),
+            Self::List(n) => {
+                let l = st.len();
+                if *n as usize > l {
+                    return e_str("Cannot take that many elements");
+                }
+                let mut res = Dist::constant(Value::List(Vec::new()));
+                for d in st.split_off(l - *n as usize) {
+                    res = res.combine(&d, |a, b| {
+                        let mut l = a.clone().as_list();
+                        l.push(b.clone());
+                        Ok(Value::List(l))
+                    })?;
+                }
+                st.push(res);
+            }
+            Self::Add => dist2!(st, a, b, Value::Num(dice::add(a.as_int()?, b.as_int()?)?)),
+            Self::Sub => dist2!(st, a, b, Value::Num(dice::sub(a.as_int()?, b.as_int()?)?)),
+            Self::Mul => dist2!(st, a, b, Value::Num(dice::mul(a.as_int()?, b.as_int()?)?)),
+            Self::Div(r) => dist2!(st, a, b, Value::Num(r.div(a.as_int()?, b.as_int()?)?)),
+            Self::Mod => dist2!(st, a, b, Value::Num(modulo(a.as_int()?, b.as_int()?)?)),
+            Self::Append => dist2!(st, a, b, a.clone().append(b.clone())),
+            Self::Range => dist2!(st, a, b, Value::Range(a.as_int()?, b.as_int()?)),
+            Self::Neg => {
+                let a = st.pop().e_str("Nothing on Stack")?;
+                st.push(a.map(|v| Ok(Value::Num(dice::neg(v.as_int()?)?)))?);
+            }
+            Self::Sum => {
+                let a = st.pop().e_str("Nothing on Stack")?;
+                st.push(a.map(|v| v.as_int().map(Value::Num))?);
+            }
+            Self::Count => {
+                let a = st.pop().e_str("Nothing on Stack")?;
+                st.push(a.map(|v| Ok(v.count()))?);
+            }
+            Self::HighestN => dist2!(st, a, b, a.clone().highest_n(b.as_int()? as usize)),
+            Self::LowestN => dist2!(st, a, b, a.clone().lowest_n(b.as_int()? as usize)),
+            Self::Equal => dist2!(st, a, b, a.filter(|v| v == b)),
+            Self::Less => dist2!(st, a, b, a.filter(|v| v < b)),
+            Self::Greater => dist2!(st, a, b, a.filter(|v| v > b)),
+            Self::LessEq => dist2!(st, a, b, a.filter(|v| v <= b)),
+            Self::GreaterEq => dist2!(st, a, b, a.filter(|v| v >= b)),
+            Self::Is(c) => dist2!(st, a, b, Value::Bool(a.test(*c, b))),
+            Self::And => dist2!(st, a, b, Value::Bool(a.as_bool()? && b.as_bool()?)),
+            Self::Or => dist2!(st, a, b, Value::Bool(a.as_bool()? || b.as_bool()?)),
+            Self::Not => {
+                let a = st.pop().e_str("Nothing on Stack")?;
+                st.push(a.map(|v| Ok(Value::Bool(!v.as_bool()?)))?);
+            }
+            Self::D => {
+                let d = st.pop().e_str("Nothing on Stack")?;
+                let n = st.pop().e_str("Nothing on Stack")?;
+                st.push(d.roll_n(&n)?);
+            }
+            Self::ZeroD => {
+                let d = st.pop().e_str("Nothing on Stack")?;
+                let n = st.pop().e_str("Nothing on Stack")?;
+                st.push(d.map(|v| v.zero_based())?.roll_n(&n)?);
+            }
+            Self::Percentile => {
+                let n = st.pop().e_str("Nothing on Stack")?;
+                st.push(Dist::constant(Value::Num(100)).roll_n(&n)?);
+            }
+            Self::Repeat(_) => return e_str("Cannot compute exact distribution of repeat"),
+            Self::Call(name, _) => {
+                return e_string(format!(
+                    "Cannot compute exact distribution of a call to '{}'",
+                    name
+                ))
+            }
+            op => return e_string(format!("Cannot compute exact distribution of {:?}", op)),
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    /// The exact distribution of results, without rolling any dice
+    pub fn dist(&self) -> anyhow::Result<Dist> {
+        let mut st = Vec::new();
+        for o in &self.ops {
+            o.dist(&mut st)?;
+        }
+        st.pop().e_str("Nothing on Stack")
+    }
+}
+
+#[cfg(test)]
+mod dist_test {
+    use super::*;
+    use crate::parser::parse_expr;
+
+    fn dist(s: &str) -> Dist {
+        parse_expr(s).unwrap().dist().unwrap()
+    }
+
+    fn close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    pub fn test_dist() {
+        let d = dist("2d6");
+        close(d.mean().unwrap(), 7.);
+        close(d.variance().unwrap(), 35. / 6.);
+        assert_eq!(d.min().unwrap(), 2);
+        assert_eq!(d.max().unwrap(), 12);
+        close(d.at_least(12).unwrap(), 1. / 36.);
+        close(d.at_least(2).unwrap(), 1.);
+
+        close(dist("4d6 h 3").mean().unwrap(), 15869. / 1296.);
+        close(dist("1d20 + 5 - 1").mean().unwrap(), 14.5);
+        close(dist("(3d6 > 4)!").mean().unwrap(), 1.);
+        close(dist("2d6 l 1").at_least(6).unwrap(), 1. / 36.);
+        close(dist("1d6 * 2").mean().unwrap(), 7.);
+        close(dist("1d10").mean().unwrap(), 5.5);
+        close(dist("1z10").mean().unwrap(), 4.5);
+        close(dist("2d%").mean().unwrap(), 101.);
+        close(dist("1d20 + 5 ge 15").mean().unwrap(), 0.55);
+        close(dist("1d6 gt 3 and 1d6 gt 3").mean().unwrap(), 0.25);
+        close(dist("sum(3d6)").mean().unwrap(), 10.5);
+
+        assert!(parse_expr("3d6!").unwrap().dist().is_err());
+        let e = parse_expr("repeat 2 1d2").unwrap().dist().unwrap_err();
+        assert_eq!(e.to_string(), "Cannot compute exact distribution of repeat");
+        let e = parse_expr("f(1d2)").unwrap().dist().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Cannot compute exact distribution of a call to 'f'"
+        );
+        close(dist("30d3").mean().unwrap(), 60.);
+        for s in ["2147483647d1", "5000d2", "30d6", "100d3"] {
+            let e = parse_expr(s).unwrap().dist().unwrap_err();
+            assert_eq!(e.to_string(), "Too many outcomes to compute exactly");
+        }
+        let e = parse_expr("1d2000000000").unwrap().dist().unwrap_err();
+        assert_eq!(e.to_string(), "Too many outcomes to compute exactly");
+        assert!(parse_expr("1d-2000000000..2000000000")
+            .unwrap()
+            .dist()
+            .is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use crate::limits::Limit;
+use std::fmt::{self, Display};
+
+/// Byte offsets of a piece of source text
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Unexpected { expected: String, found: String },
+    Message(String),
+    LimitExceeded(Limit, usize),
+    Overflow,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unexpected { expected, found } => {
+                write!(f, "Expected {}, found {}", expected, found)
+            }
+            Self::Message(s) => write!(f, "{}", s),
+            Self::Overflow => write!(f, "Number too large"),
+            Self::LimitExceeded(l, max) => write!(f, "Limit exceeded: more than {} {}", max, l),
+        }
+    }
+}
+
+/// An error that knows where in the source it happened.
+/// Once given the source it displays the offending line with a caret underline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiceError {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    src: Option<String>,
+}
+
+impl DiceError {
+    pub fn new(kind: ErrorKind, span: Option<Span>) -> Self {
+        Self {
+            kind,
+            span,
+            src: None,
+        }
+    }
+
+    pub fn message<S: Into<String>>(s: S, span: Span) -> Self {
+        Self::new(ErrorKind::Message(s.into()), Some(span))
+    }
+
+    pub fn unexpected<S: Into<String>, F: Into<String>>(expected: S, found: F, span: Span) -> Self {
+        Self::new(
+            ErrorKind::Unexpected {
+                expected: expected.into(),
+                found: found.into(),
+            },
+            Some(span),
+        )
+    }
+
+    /// Give an error a span if it does not already have one
+    pub fn locate(e: anyhow::Error, span: Option<Span>) -> anyhow::Error {
+        match e.downcast::<DiceError>() {
+            Ok(mut d) => {
+                d.span = d.span.or(span);
+                d.into()
+            }
+            Err(e) => DiceError::new(ErrorKind::Message(e.to_string()), span).into(),
+        }
+    }
+}
+
+/// Attach the source text to a DiceError so it can show where it happened
+pub fn in_source(e: anyhow::Error, src: &str) -> anyhow::Error {
+    match e.downcast::<DiceError>() {
+        Ok(mut d) => {
+            d.src = Some(src.to_string());
+            d.into()
+        }
+        Err(e) => e,
+    }
+}
+
+impl Display for DiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        let (sp, src) = match (&self.span, &self.src) {
+            (Some(sp), Some(src)) => (sp, src),
+            _ => return Ok(()),
+        };
+        let start = sp.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or(src.len());
+        let line_no = src[..line_start].matches('\n').count() + 1;
+        let col = src[line_start..start].chars().count();
+        let end = sp.end.clamp(start, line_end);
+        let width = src[start..end].chars().count().max(1);
+        write!(
+            f,
+            "\n{:>4} | {}\n     | {}{}",
+            line_no,
+            &src[line_start..line_end],
+            " ".repeat(col),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl std::error::Error for DiceError {}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+
+    #[test]
+    pub fn test_render() {
+        let e = DiceError::unexpected("a value", "'+'", Span::new(6, 7));
+        let e = in_source(e.into(), "1d6 + + 2");
+        assert_eq!(
+            e.to_string(),
+            "Expected a value, found '+'\n   1 | 1d6 + + 2\n     |       ^"
+        );
+        let e = DiceError::message("Could not get var 'x'", Span::new(6, 8));
+        let e = in_source(e.into(), "1\n2 + $x");
+        assert_eq!(
+            e.to_string(),
+            "Could not get var 'x'\n   2 | 2 + $x\n     |     ^^"
+        );
+    }
+}
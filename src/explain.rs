@@ -0,0 +1,216 @@
+use crate::dice::Value;
+use crate::expr::Operation;
+use std::fmt::{self, Display};
+
+/// What happened to a single rolled die
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DieMark {
+    Kept,
+    Dropped,
+    Rerolled,
+    Exploded,
+}
+
+/// One step of an evaluation: the operation, the value it produced,
+/// the steps whose values it used, and what happened to any dice it handled
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub op: Option<Operation>,
+    pub value: Value,
+    pub inputs: Vec<Node>,
+    pub dice: Vec<(Value, DieMark)>,
+}
+
+/// Builds Nodes alongside the Context stack while an expression resolves
+#[derive(Clone, Debug, Default)]
+pub struct Explainer {
+    nodes: Vec<Node>,
+    inputs: Vec<Node>,
+    dice: Vec<(Value, DieMark)>,
+    op: Option<Operation>,
+}
+
+impl Explainer {
+    pub fn begin(&mut self, op: &Operation) {
+        self.end();
+        self.op = Some(op.clone());
+    }
+
+    /// Clear anything the last operation used without producing a value
+    pub fn end(&mut self) {
+        self.inputs.clear();
+        self.dice.clear();
+        self.op = None;
+    }
+
+    pub fn pop(&mut self) {
+        if let Some(n) = self.nodes.pop() {
+            self.inputs.push(n);
+        }
+    }
+
+    pub fn pop_n(&mut self, n: usize) {
+        let l = self.nodes.len().saturating_sub(n);
+        let mut popped = self.nodes.split_off(l);
+        popped.reverse();
+        self.inputs.extend(popped);
+    }
+
+    pub fn push(&mut self, v: &Value) {
+        let mut inputs = std::mem::take(&mut self.inputs);
+        inputs.reverse();
+        self.nodes.push(Node {
+            op: self.op.clone(),
+            value: v.clone(),
+            inputs,
+            dice: std::mem::take(&mut self.dice),
+        });
+    }
+
+    pub fn mark(&mut self, v: Value, m: DieMark) {
+        self.dice.push((v, m));
+    }
+
+    /// Mark each die in the pool as kept if it made it into `kept`, or dropped if not
+    pub fn mark_kept(&mut self, pool: &Value, kept: &Value) {
+        let mut kept = kept.clone().as_list();
+        for d in pool.clone().as_list() {
+            match kept.iter().position(|k| *k == d) {
+                Some(p) => {
+                    kept.remove(p);
+                    self.mark(d, DieMark::Kept);
+                }
+                None => self.mark(d, DieMark::Dropped),
+            }
+        }
+    }
+
+    /// Take the Node of the value most recently popped
+    pub fn take_popped(&mut self) -> Option<Node> {
+        self.inputs.pop()
+    }
+}
+
+impl Node {
+    fn arith(&self) -> Option<&'static str> {
+        match self.op {
+            Some(Operation::Add) => Some("+"),
+            Some(Operation::Sub) => Some("-"),
+            Some(Operation::Mul) => Some("*"),
+            Some(Operation::Div(_)) => Some("/"),
+            Some(Operation::Mod) => Some("%"),
+            _ => None,
+        }
+    }
+
+    fn render_dice(&self) -> String {
+        let mut res = String::new();
+        let mut comma = "[";
+        for (v, m) in &self.dice {
+            res.push_str(comma);
+            match m {
+                DieMark::Kept => res.push_str(&v.to_string()),
+                DieMark::Dropped => res.push_str(&format!("~~{}~~", v)),
+                DieMark::Rerolled => res.push_str(&format!("~~{}~~r", v)),
+                DieMark::Exploded => res.push_str(&format!("{}!", v)),
+            }
+            comma = ", ";
+        }
+        res.push(']');
+        res
+    }
+
+    /// Render as an operand of the parent symbol, showing the total of any pool
+    fn render_operand(&self, parent: &str, right: bool) -> String {
+        let level = |s: &str| match s {
+            "+" | "-" => 1,
+            _ => 2,
+        };
+        let s = self.render();
+        match self.arith() {
+            Some(sym) if level(sym) < level(parent) => format!("({})", s),
+            Some(sym)
+                if right && level(sym) == level(parent) && matches!(parent, "-" | "/" | "%") =>
+            {
+                format!("({})", s)
+            }
+            Some(_) => s,
+            None => match (&self.value, self.value.as_int()) {
+                (Value::List(l), Ok(n)) if l.len() > 1 || !self.dice.is_empty() => {
+                    format!("{} = {}", s, n)
+                }
+                _ => s,
+            },
+        }
+    }
+
+    /// Render the steps that produced this value, without the final result
+    pub fn render(&self) -> String {
+        if let Some(sym) = self.arith() {
+            if let [a, b] = &self.inputs[..] {
+                return format!(
+                    "{} {} {}",
+                    a.render_operand(sym, false),
+                    sym,
+                    b.render_operand(sym, true)
+                );
+            }
+        }
+        match &self.op {
+            _ if !self.dice.is_empty() => self.render_dice(),
+            Some(Operation::Neg) => match &self.inputs[..] {
+                [a] if a.arith().is_some() => format!("-({})", a.render()),
+                [a] => format!("-{}", a.render()),
+                _ => self.value.to_string(),
+            },
+            _ => self.value.to_string(),
+        }
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.render();
+        let v = self.value.to_string();
+        match &self.value {
+            // pools already end with their total
+            Value::List(_) if self.arith().is_none() => {
+                write!(f, "{}", self.render_operand("", false))
+            }
+            _ if s == v => write!(f, "{}", v),
+            _ => write!(f, "{} = {}", s, v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod explain_test {
+    use crate::context::Context;
+    use crate::parser::parse_expr;
+
+    fn explain(s: &str) -> String {
+        let (_, n) = parse_expr(s).unwrap().explain(&mut Context::new()).unwrap();
+        n.to_string()
+    }
+
+    #[test]
+    pub fn test_explain() {
+        assert_eq!(explain("[6, 4, 2, 3] h 3"), "[6, 4, ~~2~~, 3] = 13");
+        assert_eq!(
+            explain("[6, 4, 2, 3] h 3 + 2"),
+            "[6, 4, ~~2~~, 3] = 13 + 2 = 15"
+        );
+        let (v, n) = parse_expr("4d6 h 3 + 2")
+            .unwrap()
+            .explain(&mut Context::seeded(2))
+            .unwrap();
+        let s = n.to_string();
+        assert_eq!(s.matches("~~").count(), 2);
+        assert!(s.ends_with(&format!(" + 2 = {}", v)));
+        assert_eq!(explain("2d[3] * (1 + 2)"), "[3, 3] = 6 * (1 + 2) = 18");
+        assert_eq!(explain("3d[4] > 3"), "[4, 4, 4] = 12");
+        assert_eq!(explain("2 - (3 + 4)"), "2 - (3 + 4) = -5");
+        assert_eq!(explain("-2d[3]"), "-[3, 3] = -6");
+        assert_eq!(explain("7"), "7");
+    }
+}
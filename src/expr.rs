@@ -1,8 +1,13 @@
+use crate::builtin;
 use crate::context::Context;
-use crate::dice::Value;
+use crate::dice::{self, modulo, Compare, Round, Successes, Value};
+use crate::error::{DiceError, Span};
+use crate::explain::{DieMark, Node};
+use crate::limits::Limit;
 use err_tools::*;
+use std::collections::BTreeMap;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Operation {
     Num(i32),
     Word(String),
@@ -11,22 +16,85 @@ pub enum Operation {
     Add,
     Append,
     Sub,
+    Mul,
+    Div(Round),
+    Mod,
     Neg,
     L,
     H,
     P,
     Fudge,
     D,
+    ZeroD,      //Dice numbered from 0, like z10
+    Percentile, //d%, a tens and a units die
     Sum,
     Equal,
     Less,
     Greater,
+    LessEq,
+    GreaterEq,
     Range,
     Replace,
     Count,
     As,
     HighestN,
     LowestN,
+    Explode(Option<Compare>), //None explodes on the max face
+    Compound(Option<Compare>),
+    Penetrate(Option<Compare>),
+    RerollOnce(Compare),
+    Reroll(Compare),
+    Successes(Compare, Option<Compare>, Option<Compare>), //hit, double, fail
+    Def(Box<Func>),
+    Call(String, usize), //Num args
+    Let(String),         //Bind the top value in a new scope
+    Scope,
+    EndScope,
+    Bool(bool),
+    Is(Compare), //Compare two values to true or false
+    And,
+    Or,
+    Not,
+    Jump(usize),       //Index of the op to run next
+    JumpUnless(usize), //Jump if the top value is false
+    Repeat(Box<Expr>), //Run the body the popped number of times, listing the results
+}
+
+/// A function defined in the language, run with each parameter bound to an argument
+#[derive(Clone, Debug, PartialEq)]
+pub struct Func {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+}
+
+fn plural(n: usize, s: &str) -> String {
+    match n {
+        1 => format!("{} {}", n, s),
+        n => format!("{} {}s", n, s),
+    }
+}
+
+/// Errors unless the function was given as many arguments as it takes
+pub fn check_args(name: &str, want: usize, got: usize) -> anyhow::Result<()> {
+    if want != got {
+        return e_string(format!(
+            "{} takes {} but was given {}",
+            name,
+            plural(want, "argument"),
+            plural(got, "argument")
+        ));
+    }
+    Ok(())
+}
+
+/// Run a function defined in the language, leaving its result on the stack
+pub fn call_func(ct: &mut Context, f: &Func, args: Vec<Value>) -> anyhow::Result<()> {
+    check_args(&f.name, f.params.len(), args.len())?;
+    ct.push_frame(f.params.iter().cloned().zip(args).collect())?;
+    let res = f.body.run(ct);
+    ct.pop_frame();
+    res
 }
 
 macro_rules! job2 {
@@ -37,18 +105,119 @@ macro_rules! job2 {
     }};
 }
 
+/// Filter the pool by comparing each die to the target, marking which dice passed
+fn filter_job(ct: &mut Context, c: Compare) -> anyhow::Result<()> {
+    let b = ct.try_pop()?;
+    let a = ct.try_pop()?;
+    let kept = a.filter(|v| c.test(v, &b));
+    ct.mark_kept(&a, &kept);
+    ct.push(kept);
+    Ok(())
+}
+
+/// Pop the count, die and optional trigger for a dice modifier,
+/// defaulting the trigger to the die's max face
+fn pop_modified(
+    ct: &mut Context,
+    c: &Option<Compare>,
+) -> anyhow::Result<(i32, Value, Compare, Value)> {
+    let t = match c {
+        Some(c) => Some((*c, ct.try_pop()?)),
+        None => None,
+    };
+    let d = ct.try_pop()?;
+    d.check_die()?;
+    let n = ct.try_pop()?.as_int()?;
+    ct.count_dice(n)?;
+    let (c, t) = match t {
+        Some(t) => t,
+        None => (Compare::Equal, d.max_face()?),
+    };
+    Ok((n, d, c, t))
+}
+
+/// Roll each die, rolling it again while the trigger holds.
+/// Returns the chain of rolls for each die, logging every extra roll
+fn roll_chains(ct: &mut Context, c: &Option<Compare>) -> anyhow::Result<Vec<Vec<Value>>> {
+    let (n, d, c, t) = pop_modified(ct, c)?;
+    let mut res = Vec::new();
+    for _ in 0..n {
+        let mut v = d.roll(ct.rng());
+        let mut chain = Vec::new();
+        while c.test(&v, &t) {
+            ct.limits().check(Limit::Rerolls, chain.len() + 1)?;
+            ct.count_dice(1)?;
+            ct.mark_die(&v, DieMark::Exploded);
+            chain.push(v);
+            v = d.roll(ct.rng());
+            ct.log_roll(v.clone());
+        }
+        ct.mark_die(&v, DieMark::Kept);
+        chain.push(v);
+        res.push(chain);
+    }
+    Ok(res)
+}
+
+fn list_len(v: &Value) -> usize {
+    match v {
+        Value::List(l) => l.len(),
+        _ => 1,
+    }
+}
+
 impl Operation {
+    /// How many values the operation takes off the stack
+    pub fn arity(&self) -> usize {
+        let opt = |c: &Option<Compare>| c.is_some() as usize;
+        match self {
+            Self::Num(_) | Self::Word(_) | Self::L | Self::H | Self::P | Self::Fudge => 0,
+            Self::Def(_) | Self::Scope | Self::EndScope => 0,
+            Self::Bool(_) | Self::Jump(_) => 0,
+            Self::Call(_, n) => *n,
+            Self::Var | Self::Neg | Self::Sum | Self::Count | Self::Percentile => 1,
+            Self::Let(_) | Self::Not | Self::JumpUnless(_) | Self::Repeat(_) => 1,
+            Self::List(n) => *n as usize,
+            Self::Explode(c) | Self::Compound(c) | Self::Penetrate(c) => 2 + opt(c),
+            Self::RerollOnce(_) | Self::Reroll(_) => 3,
+            Self::Successes(_, double, fail) => 2 + opt(double) + opt(fail),
+            _ => 2,
+        }
+    }
+
     pub fn resolve(&self, ct: &mut Context) -> anyhow::Result<()> {
+        ct.step()?;
         match self {
-            Self::Add => job2!(ct, a, b, Value::Num(a.as_int()? + b.as_int()?)),
-            Self::Append => job2!(ct, a, b, a.append(b)),
+            Self::Add => job2!(ct, a, b, Value::Num(dice::add(a.as_int()?, b.as_int()?)?)),
+            Self::Append => {
+                let b = ct.try_pop()?;
+                let a = ct.try_pop()?;
+                ct.limits()
+                    .check(Limit::ListLen, list_len(&a) + list_len(&b))?;
+                ct.push(a.append(b));
+            }
             Self::Neg => {
                 let a = ct.try_pop()?;
-                ct.push(Value::Num(-a.as_int()?));
+                ct.push(Value::Num(dice::neg(a.as_int()?)?));
+            }
+            Self::LowestN => {
+                let n = ct.try_pop()?.as_int()? as usize;
+                let a = ct.try_pop()?;
+                let kept = a.clone().lowest_n(n);
+                ct.mark_kept(&a, &kept);
+                ct.push(kept);
+            }
+            Self::HighestN => {
+                let n = ct.try_pop()?.as_int()? as usize;
+                let a = ct.try_pop()?;
+                let kept = a.clone().highest_n(n);
+                ct.mark_kept(&a, &kept);
+                ct.push(kept);
             }
-            Self::LowestN => job2!(ct, a, b, a.lowest_n(b.as_int()? as usize)),
-            Self::HighestN => job2!(ct, a, b, a.highest_n(b.as_int()? as usize)),
-            Self::Sub => job2!(ct, a, b, Value::Num(a.as_int()? - b.as_int()?)),
+            Self::Sub => job2!(ct, a, b, Value::Num(dice::sub(a.as_int()?, b.as_int()?)?)),
+            Self::Mul => job2!(ct, a, b, Value::Num(dice::mul(a.as_int()?, b.as_int()?)?)),
+            Self::Div(r) => job2!(ct, a, b, Value::Num(r.div(a.as_int()?, b.as_int()?)?)),
+            Self::Mod => job2!(ct, a, b, Value::Num(modulo(a.as_int()?, b.as_int()?)?)),
             Self::Sum => {
                 let a = ct.try_pop()?;
                 ct.push(Value::Num(a.as_int()?));
@@ -76,16 +245,115 @@ impl Operation {
                 ct.try_pop()?;
                 ct.push(v);
             }
-            Self::Equal => job2!(ct, a, b, a.filter(|v| *v == b)),
-            Self::Less => job2!(ct, a, b, a.filter(|v| *v < b)),
-            Self::Greater => job2!(ct, a, b, a.filter(|v| *v > b)),
+            Self::Equal => filter_job(ct, Compare::Equal)?,
+            Self::Less => filter_job(ct, Compare::Less)?,
+            Self::Greater => filter_job(ct, Compare::Greater)?,
+            Self::LessEq => filter_job(ct, Compare::LessEq)?,
+            Self::GreaterEq => filter_job(ct, Compare::GreaterEq)?,
             Self::D => {
                 let d = ct.try_pop()?;
+                d.check_die()?;
                 let n = ct.try_pop()?.as_int()?;
+                ct.count_dice(n)?;
                 //todo flatten
-                let r = d.roll_n(n);
+                let r = d.roll_n(n, ct.rng());
+                ct.mark_dice(&r, DieMark::Kept);
+                ct.push_roll(r);
+            }
+            Self::ZeroD => {
+                let d = ct.try_pop()?.zero_based()?;
+                let n = ct.try_pop()?.as_int()?;
+                ct.count_dice(n)?;
+                let r = d.roll_n(n, ct.rng());
+                ct.mark_dice(&r, DieMark::Kept);
                 ct.push_roll(r);
             }
+            Self::Percentile => {
+                let n = ct.try_pop()?.as_int()?;
+                ct.count_dice(n.saturating_mul(2))?;
+                let mut res = Vec::new();
+                for _ in 0..n {
+                    let (v, tens, units) = dice::roll_percentile(ct.rng());
+                    ct.log_roll(Value::List(vec![Value::Num(tens), Value::Num(units)]));
+                    ct.mark_die(&Value::Num(v), DieMark::Kept);
+                    res.push(Value::Num(v));
+                }
+                // one die is a plain number, as with roll_n
+                let r = match n {
+                    1 => res.remove(0),
+                    _ => Value::List(res),
+                };
+                ct.push_roll(r);
+            }
+            Self::Explode(c) => {
+                let res = roll_chains(ct, c)?.into_iter().flatten().collect();
+                ct.push_roll(Value::List(res));
+            }
+            Self::Compound(c) => {
+                let mut res = Vec::new();
+                for chain in roll_chains(ct, c)? {
+                    res.push(Value::List(chain).as_int().map(Value::Num)?);
+                }
+                ct.push_roll(Value::List(res));
+            }
+            Self::Penetrate(c) => {
+                let mut res = Vec::new();
+                for chain in roll_chains(ct, c)? {
+                    let extra = chain.len() as i32 - 1;
+                    res.push(Value::Num(dice::sub(Value::List(chain).as_int()?, extra)?));
+                }
+                ct.push_roll(Value::List(res));
+            }
+            Self::RerollOnce(c) => {
+                let (n, d, c, t) = pop_modified(ct, &Some(*c))?;
+                let mut res = Vec::new();
+                for _ in 0..n {
+                    let mut v = d.roll(ct.rng());
+                    if c.test(&v, &t) {
+                        ct.count_dice(1)?;
+                        ct.mark_die(&v, DieMark::Rerolled);
+                        v = d.roll(ct.rng());
+                        ct.log_roll(v.clone());
+                    }
+                    ct.mark_die(&v, DieMark::Kept);
+                    res.push(v);
+                }
+                ct.push_roll(Value::List(res));
+            }
+            Self::Reroll(c) => {
+                let (n, d, c, t) = pop_modified(ct, &Some(*c))?;
+                let mut res = Vec::new();
+                for _ in 0..n {
+                    let mut v = d.roll(ct.rng());
+                    let mut rerolls = 0;
+                    while c.test(&v, &t) {
+                        rerolls += 1;
+                        ct.limits().check(Limit::Rerolls, rerolls)?;
+                        ct.count_dice(1)?;
+                        ct.mark_die(&v, DieMark::Rerolled);
+                        v = d.roll(ct.rng());
+                        ct.log_roll(v.clone());
+                    }
+                    ct.mark_die(&v, DieMark::Kept);
+                    res.push(v);
+                }
+                ct.push_roll(Value::List(res));
+            }
+            Self::Successes(hit, double, fail) => {
+                let fail = match fail {
+                    Some(c) => Some((*c, ct.try_pop()?)),
+                    None => None,
+                };
+                let double = match double {
+                    Some(c) => Some((*c, ct.try_pop()?)),
+                    None => None,
+                };
+                let hit = (*hit, ct.try_pop()?);
+                let pool = ct.try_pop()?;
+                let s = Successes::count(&pool, hit, double, fail);
+                ct.log_successes(s);
+                ct.push(Value::Successes(s));
+            }
             Self::Range => {
                 let b = ct.try_pop()?.as_int()?;
                 let a = ct.try_pop()?.as_int()?;
@@ -98,6 +366,7 @@ impl Operation {
                 ct.var(&w)?;
             }
             Self::List(n) => {
+                ct.limits().check(Limit::ListLen, *n as usize)?;
                 let l = ct.top_n(*n as usize)?;
                 ct.push(Value::List(l));
             }
@@ -105,6 +374,50 @@ impl Operation {
                 let l = ct.try_pop()?;
                 ct.push(l.count());
             }
+            Self::Def(f) => {
+                ct.define((**f).clone());
+                ct.push(Value::Word(f.name.clone()));
+            }
+            Self::Call(name, n) => {
+                let args = ct.top_n(*n)?;
+                match ct.get_func(name) {
+                    Some(f) => call_func(ct, &f, args)?,
+                    None => {
+                        let v = builtin::call(ct, name, args)?;
+                        // any functions it called ended this one's explanation
+                        ct.begin_op(self);
+                        ct.push(v);
+                    }
+                }
+            }
+            Self::Let(name) => {
+                let v = ct.try_pop()?;
+                ct.push_scope(BTreeMap::from([(name.clone(), v)]));
+            }
+            Self::Scope => ct.push_scope(BTreeMap::new()),
+            Self::EndScope => ct.pop_scope(),
+            Self::Bool(b) => ct.push(Value::Bool(*b)),
+            Self::Is(c) => job2!(ct, a, b, Value::Bool(a.test(*c, &b))),
+            Self::And => job2!(ct, a, b, Value::Bool(a.as_bool()? && b.as_bool()?)),
+            Self::Or => job2!(ct, a, b, Value::Bool(a.as_bool()? || b.as_bool()?)),
+            Self::Not => {
+                let a = ct.try_pop()?;
+                ct.push(Value::Bool(!a.as_bool()?));
+            }
+            Self::Jump(_) | Self::JumpUnless(_) => return e_str("Cannot jump outside an Expr"),
+            Self::Repeat(body) => {
+                let n = ct.try_pop()?.as_int()?.max(0) as usize;
+                ct.limits().check(Limit::ListLen, n)?;
+                let mut l = Vec::with_capacity(n);
+                for _ in 0..n {
+                    body.resolve_ops(ct)?;
+                    l.push(ct.pop_value()?);
+                }
+                // the body's ops ended this one's explanation
+                ct.begin_op(self);
+                ct.explain_inputs(n);
+                ct.push(Value::List(l));
+            }
         }
         Ok(())
     }
@@ -113,16 +426,434 @@ impl Operation {
 #[derive(Clone, Debug)]
 pub struct Expr {
     pub ops: Vec<Operation>,
+    pub spans: Vec<Span>, //Source of each op, for errors
+}
+
+/// Spans only locate errors, so two Exprs doing the same thing are equal
+impl PartialEq for Expr {
+    fn eq(&self, b: &Self) -> bool {
+        self.ops == b.ops
+    }
+}
+
+impl Default for Expr {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Expr {
     pub fn new() -> Self {
-        Self { ops: Vec::new() }
+        Self {
+            ops: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, op: Operation, sp: Span) {
+        self.ops.push(op);
+        self.spans.push(sp);
+    }
+
+    /// Push a jump whose target is not known yet, returning its index for patch_jump
+    pub fn push_jump(&mut self, op: Operation, sp: Span) -> usize {
+        self.push(op, sp);
+        self.ops.len() - 1
     }
+
+    /// Point the jump at index i to the next op to be pushed
+    pub fn patch_jump(&mut self, i: usize) {
+        let target = self.ops.len();
+        match &mut self.ops[i] {
+            Operation::Jump(t) | Operation::JumpUnless(t) => *t = target,
+            _ => {}
+        }
+    }
+
+    /// Run the op at index i, returning the index of the op to run next
+    fn step(&self, ct: &mut Context, i: usize) -> anyhow::Result<usize> {
+        let o = &self.ops[i];
+        ct.begin_op(o);
+        let next = match o {
+            Operation::Jump(t) => {
+                ct.step()?;
+                *t
+            }
+            Operation::JumpUnless(t) => {
+                ct.step()?;
+                match ct.try_pop()?.as_bool()? {
+                    true => i + 1,
+                    false => *t,
+                }
+            }
+            o => {
+                o.resolve(ct)?;
+                ct.check_stack()?;
+                i + 1
+            }
+        };
+        ct.end_op();
+        Ok(next)
+    }
+
+    /// Run the ops, leaving the result on the stack.
+    /// Errors are left for the caller to locate
+    pub fn run(&self, ct: &mut Context) -> anyhow::Result<()> {
+        let mut i = 0;
+        while i < self.ops.len() {
+            i = self.step(ct, i)?;
+        }
+        Ok(())
+    }
+
     pub fn resolve(&self, ct: &mut Context) -> anyhow::Result<Value> {
-        for o in &self.ops {
-            o.resolve(ct)?;
+        ct.reset_counts();
+        let depth = ct.scope_depth();
+        let len = ct.stack().len();
+        let res = self.resolve_ops(ct);
+        ct.truncate_scopes(depth);
+        if res.is_err() {
+            ct.truncate_stack(len);
         }
+        res?;
         ct.try_pop()
     }
+
+    fn resolve_ops(&self, ct: &mut Context) -> anyhow::Result<()> {
+        let mut i = 0;
+        while i < self.ops.len() {
+            i = self
+                .step(ct, i)
+                .map_err(|e| DiceError::locate(e, self.spans.get(i).copied()))?;
+        }
+        Ok(())
+    }
+
+    /// Resolve, also building a tree explaining how the result was reached
+    pub fn explain(&self, ct: &mut Context) -> anyhow::Result<(Value, Node)> {
+        ct.explain();
+        let v = self.resolve(ct)?;
+        let n = ct.take_explanation().e_str("Nothing to explain")?;
+        Ok((v, n))
+    }
+}
+
+#[cfg(test)]
+mod expr_test {
+    use super::*;
+    use crate::parser::parse_expr;
+
+    fn run(s: &str) -> anyhow::Result<Value> {
+        parse_expr(s)?.resolve(&mut Context::new())
+    }
+
+    #[test]
+    pub fn test_seeded() {
+        let e = parse_expr("10d20 ++ 3d6!").unwrap();
+        let a = e.resolve(&mut Context::seeded(3)).unwrap();
+        let b = e.resolve(&mut Context::seeded(3)).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    pub fn test_explode() {
+        let e = parse_expr("3d6!").unwrap();
+        assert_eq!(
+            e.ops,
+            vec![
+                Operation::Num(3),
+                Operation::Num(6),
+                Operation::Explode(None)
+            ]
+        );
+        let e = parse_expr("3d6e>=5").unwrap();
+        assert_eq!(e.ops[3], Operation::Explode(Some(Compare::GreaterEq)));
+        assert_eq!(
+            run("2d[5]!<5").unwrap(),
+            Value::List(vec![Value::Num(5), Value::Num(5)])
+        );
+        assert!(run("1d[6]!").is_err());
+        assert_eq!(run("(3d6)!").unwrap(), Value::Num(3));
+    }
+
+    #[test]
+    pub fn test_compound_penetrate() {
+        let e = parse_expr("3d6!!").unwrap();
+        assert_eq!(e.ops[2], Operation::Compound(None));
+        let e = parse_expr("3d6!p>4").unwrap();
+        assert_eq!(e.ops[3], Operation::Penetrate(Some(Compare::Greater)));
+        let mut ct = Context::new();
+        let v = parse_expr("4d[5,6]!!6").unwrap().resolve(&mut ct).unwrap();
+        for d in v.as_list() {
+            // a compounded die only stops on a 5
+            assert_eq!(d.as_int().unwrap() % 6, 5);
+        }
+        let mut ct = Context::new();
+        let v = parse_expr("4d[5,6]!p6").unwrap().resolve(&mut ct).unwrap();
+        for d in v.as_list() {
+            assert_eq!(d.as_int().unwrap() % 5, 0);
+        }
+    }
+
+    #[test]
+    pub fn test_reroll() {
+        let e = parse_expr("2d6r1").unwrap();
+        assert_eq!(e.ops[3], Operation::RerollOnce(Compare::Equal));
+        let e = parse_expr("2d6ro<3").unwrap();
+        assert_eq!(e.ops[3], Operation::RerollOnce(Compare::Less));
+        assert!(parse_expr("2d6r").is_err());
+        let v = run("10d[1,2]rr1").unwrap();
+        assert_eq!(v, Value::List(vec![Value::Num(2); 10]));
+        assert!(run("1d6rr<7").is_err());
+    }
+
+    #[test]
+    pub fn test_arithmetic() {
+        assert_eq!(run("2 + 3 * 4").unwrap(), Value::Num(14));
+        assert_eq!(run("20 - 6 / 2 * 3").unwrap(), Value::Num(11));
+        assert_eq!(run("7 / 2").unwrap(), Value::Num(3));
+        assert_eq!(run("7 /^ 2").unwrap(), Value::Num(4));
+        assert_eq!(run("7 /~ 2").unwrap(), Value::Num(4));
+        assert_eq!(run("5 /~ 3").unwrap(), Value::Num(2));
+        assert_eq!(run("(0 - 7) / 2").unwrap(), Value::Num(-4));
+        assert_eq!(run("(0 - 7) /^ 2").unwrap(), Value::Num(-3));
+        assert_eq!(run("13 % 6").unwrap(), Value::Num(1));
+        assert_eq!(run("(0 - 1) % 6").unwrap(), Value::Num(5));
+        assert_eq!(run("[2, 3] * 10").unwrap(), Value::Num(50));
+        assert!(run("1d20 / 0").is_err());
+        assert!(run("1d20 % 0").is_err());
+    }
+
+    #[test]
+    pub fn test_overflow() {
+        let overflows = |s: &str| {
+            let e = run(s).unwrap_err().downcast::<DiceError>().unwrap();
+            e.kind == crate::error::ErrorKind::Overflow
+        };
+        assert!(overflows("2147483647 + 1"));
+        assert!(overflows("-2147483648 - 1"));
+        assert!(overflows("65536 * 65536"));
+        assert!(overflows("[2147483647, 1] + 0"));
+        assert!(overflows("-(-2147483648)"));
+        assert!(overflows("-2147483648 / -1"));
+        assert_eq!(run("2147483647 /~ 2").unwrap(), Value::Num(1073741824));
+        assert_eq!(run("-2147483648").unwrap(), Value::Num(i32::MIN));
+        assert!(overflows("2147483648"));
+        assert!(overflows("-2147483648d6"));
+    }
+
+    #[test]
+    pub fn test_negation() {
+        assert_eq!(run("-3 + 1").unwrap(), Value::Num(-2));
+        assert_eq!(run("-2d[3]").unwrap(), Value::Num(-6));
+        assert_eq!(run("1d[5] - -2").unwrap(), Value::Num(7));
+        assert!(run("1d-6").is_err());
+        assert!(run("1d(3..3)").is_err());
+    }
+
+    #[test]
+    pub fn test_successes() {
+        let e = parse_expr("5d10s>=8f1").unwrap();
+        assert_eq!(
+            e.ops[5],
+            Operation::Successes(Compare::GreaterEq, None, Some(Compare::Equal))
+        );
+        let s = |hits, fails, botch| Value::Successes(Successes { hits, fails, botch });
+        assert_eq!(run("[1, 8, 10, 3, 1] s>=8 f1").unwrap(), s(2, 2, false));
+        assert_eq!(run("[1, 8, 10, 3] s>=8 dbl10 f1").unwrap(), s(3, 1, false));
+        assert_eq!(run("[1, 5, 3] s>=8 f1").unwrap(), s(0, 1, true));
+        assert_eq!(run("[1, 8, 10, 3, 1] s>=8 f1 + 1").unwrap(), Value::Num(1));
+    }
+
+    #[test]
+    pub fn test_dice_kinds() {
+        let faces = |s: &str| {
+            let mut ct = Context::seeded(5);
+            let v = parse_expr(s).unwrap().resolve(&mut ct).unwrap().as_list();
+            let v: Vec<i32> = v.iter().map(|v| v.as_int().unwrap()).collect();
+            (*v.iter().min().unwrap(), *v.iter().max().unwrap(), ct)
+        };
+        assert_eq!(faces("300d10").0, 1);
+        assert_eq!(faces("300d10").1, 10);
+        assert_eq!(faces("300z10").0, 0);
+        assert_eq!(faces("300z10").1, 9);
+        assert_eq!(parse_expr("3d10z").unwrap(), parse_expr("3z10").unwrap());
+        let (min, max, ct) = faces("2000d%");
+        assert_eq!((min, max), (1, 100));
+        // each percentile logs its tens and units dice, then the pool
+        assert_eq!(ct.rolls().len(), 2001);
+        assert!(run("1z0").is_err());
+        assert_eq!(run("1d[10]").unwrap(), Value::Num(10));
+        // a single die is a plain number in both engines
+        for s in ["1d10", "1z10", "1d%"] {
+            assert!(matches!(run(s).unwrap(), Value::Num(_)));
+            let d = parse_expr(s).unwrap().dist().unwrap();
+            assert!(d.outcomes().keys().all(|v| matches!(v, Value::Num(_))));
+        }
+    }
+
+    fn run_program(s: &str) -> anyhow::Result<Value> {
+        let mut ct = Context::new();
+        let mut res = Value::Num(0);
+        for e in crate::parser::parse_program(s)? {
+            res = e.resolve(&mut ct)?;
+        }
+        Ok(res)
+    }
+
+    #[test]
+    pub fn test_functions() {
+        let adv = "fn adv(x) = 2d[20] h 1 + $x;";
+        assert_eq!(
+            run_program(&format!("{} adv(5)", adv)).unwrap(),
+            Value::Num(25)
+        );
+        assert_eq!(
+            run_program(&format!("{} adv(adv(1)) + adv(0)", adv)).unwrap(),
+            Value::Num(61)
+        );
+        for _ in 0..20 {
+            let v = run_program("fn adv(x) = 2d20 h 1 + $x; adv(5)").unwrap();
+            assert!((6..=25).contains(&v.as_int().unwrap()));
+        }
+        assert_eq!(
+            run_program("5 as g; fn inc() = $g + 1; inc()").unwrap(),
+            Value::Num(6)
+        );
+        let err = |s: &str| run_program(s).unwrap_err().to_string();
+        assert_eq!(
+            err("fn id(a) = $a; id(1, 2)"),
+            "id takes 1 argument but was given 2 arguments"
+        );
+        assert_eq!(err("g(1)"), "No function called 'g'");
+        assert_eq!(
+            err("fn id(x) = $x as y; id(2); $y"),
+            "Could not get var 'y'"
+        );
+        assert_eq!(
+            err("fn id(x) = $y; fn g(y) = id(1); g(2)"),
+            "Could not get var 'y'"
+        );
+        assert_eq!(
+            err("fn loop(x) = loop($x); loop(1)"),
+            "Limit exceeded: more than 64 nested function calls"
+        );
+        assert!(parse_expr("fn id(a, a) = 1").is_err());
+        // modifier letters only act as modifiers after a roll
+        assert_eq!(
+            run_program("fn f(e) = $e + 1d[5] e 6; f(2)").unwrap(),
+            Value::Num(7)
+        );
+        assert_eq!(
+            run_program("map([1, 2], fn f(x) = $x + 1)").unwrap(),
+            Value::List(vec![Value::Num(2), Value::Num(3)])
+        );
+        assert_eq!(err("fn z(x) = 1"), "Expected a function name, found 'z'");
+    }
+
+    #[test]
+    pub fn test_scopes() {
+        assert_eq!(run("let x = 1d[5] in $x + $x").unwrap(), Value::Num(10));
+        assert_eq!(
+            run_program("3 as x; let x = 1 in $x").unwrap(),
+            Value::Num(1)
+        );
+        assert_eq!(
+            run_program("3 as x; let x = 1 in $x; $x").unwrap(),
+            Value::Num(3)
+        );
+        assert_eq!(
+            run_program("2 as a; {5 as a; $a * 2} + $a").unwrap(),
+            Value::Num(12)
+        );
+        assert_eq!(
+            run("let a = 1 in let b = 2 in {$a + $b}").unwrap(),
+            Value::Num(3)
+        );
+        assert_eq!(
+            run_program("fn inner(x) = {$x as y; $y}; 1 as y; inner(5) + $y").unwrap(),
+            Value::Num(6)
+        );
+        assert_eq!(
+            run_program("fn g() = $x; let x = 1 in g()")
+                .unwrap_err()
+                .to_string(),
+            "Could not get var 'x'"
+        );
+
+        // scopes close even when the expression errors inside them
+        let mut ct = Context::new();
+        assert!(parse_expr("let x = 1 in {$y}")
+            .unwrap()
+            .resolve(&mut ct)
+            .is_err());
+        assert!(parse_expr("$x").unwrap().resolve(&mut ct).is_err());
+        for s in ["1 as _h", "2 as v", "let w = 3 in $w as u"] {
+            parse_expr(s).unwrap().resolve(&mut ct).unwrap();
+        }
+        assert_eq!(ct.to_string(), "v 2\n");
+        assert!(parse_expr("{}").is_err());
+    }
+
+    #[test]
+    pub fn test_conditions() {
+        assert_eq!(run("3 ge 2").unwrap(), Value::Bool(true));
+        assert_eq!(run("[1, 2] eq 3").unwrap(), Value::Bool(true));
+        assert_eq!(run("\"a\" ne \"b\"").unwrap(), Value::Bool(true));
+        assert_eq!(
+            run("true and not false or false").unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(run("not 1 eq 2").unwrap(), Value::Bool(true));
+        assert_eq!(
+            run("if 1d[20] + 5 ge 15 then 2d[6] + 1 else 0").unwrap(),
+            Value::Num(13)
+        );
+        assert_eq!(run("if true then 1 else $missing").unwrap(), Value::Num(1));
+
+        // only the branch taken rolls
+        let mut ct = Context::new();
+        let e = parse_expr("if 1 gt 2 then 1d6 else 2").unwrap();
+        assert_eq!(e.resolve(&mut ct).unwrap(), Value::Num(2));
+        assert!(ct.rolls().is_empty());
+
+        assert_eq!(
+            run_program("fn fact(n) = if $n le 1 then 1 else $n * fact($n - 1); fact(5)").unwrap(),
+            Value::Num(120)
+        );
+        assert_eq!(
+            run("if 5 then 1 else 2").unwrap_err().to_string(),
+            "Expected true or false, found 5"
+        );
+        assert!(parse_expr("if true then 1").is_err());
+    }
+
+    #[test]
+    pub fn test_repeat() {
+        assert_eq!(
+            run("repeat 3 (1d[5] + 1)").unwrap(),
+            Value::List(vec![Value::Num(6); 3])
+        );
+        assert_eq!(run("repeat 0 1d6").unwrap(), Value::List(Vec::new()));
+        // results are collected as they come, so the stack stays shallow
+        assert_eq!(run("repeat 2000 1").unwrap().as_list().len(), 2000);
+
+        // each run rolls afresh
+        let mut ct = Context::seeded(4);
+        let v = parse_expr("repeat 20 1d20")
+            .unwrap()
+            .resolve(&mut ct)
+            .unwrap();
+        let mut l = v.as_list();
+        assert_eq!(l.len(), 20);
+        assert_eq!(ct.rolls().len(), 20);
+        l.dedup();
+        assert!(l.len() > 1);
+
+        let e = parse_expr("repeat 2 (1 + $y)")
+            .unwrap()
+            .resolve(&mut Context::new())
+            .unwrap_err();
+        let d = e.downcast::<DiceError>().unwrap();
+        assert_eq!(d.span, Some(Span::new(14, 16)));
+    }
 }
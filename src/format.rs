@@ -0,0 +1,459 @@
+use crate::ast::{Ast, AstKind};
+use crate::dice::{Compare, Round};
+use crate::expr::Operation;
+use crate::parser::parse_program_ast;
+use crate::tokenizer::TokenType;
+use err_tools::*;
+use std::fmt::{self, Display};
+
+/// Parse a program and print it back in canonical form, one statement per line.
+/// Comments are not kept.
+pub fn format(s: &str) -> anyhow::Result<String> {
+    let mut res = String::new();
+    for a in parse_program_ast(s)? {
+        res.push_str(&a.canonical()?);
+        res.push('\n');
+    }
+    Ok(res)
+}
+
+/// What the end of some formatted text would do to a token following it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tail {
+    Closed,
+    Dice,    //A '!' would make it explode
+    Trigger, //A comparison would become its trigger
+    Bang,    //A '!' would make '!!'
+}
+
+struct Shown {
+    s: String,
+    prec: i32,
+    tail: Tail,
+}
+
+const ATOM: i32 = 100;
+
+impl Shown {
+    fn new(s: String, prec: i32, tail: Tail) -> Self {
+        Self { s, prec, tail }
+    }
+
+    fn paren(self) -> Self {
+        Self::new(format!("({})", self.s), ATOM, Tail::Closed)
+    }
+}
+
+/// Join two pieces of text that are not spaced apart, keeping words separate
+fn join(a: &str, b: &str) -> String {
+    let word = |c: Option<char>| c.map(|c| c.is_alphabetic() || c == '_') == Some(true);
+    match word(a.chars().last()) && word(b.chars().next()) {
+        true => format!("{} {}", a, b),
+        false => format!("{}{}", a, b),
+    }
+}
+
+fn infix(op: &Operation) -> Option<(TokenType<'static>, &'static str)> {
+    use Operation::*;
+    let t = match op {
+        Add => (TokenType::Add, "+"),
+        Append => (TokenType::Append, "++"),
+        Sub => (TokenType::Sub, "-"),
+        Mul => (TokenType::Mul, "*"),
+        Div(Round::Down) => (TokenType::Div(Round::Down), "/"),
+        Div(Round::Up) => (TokenType::Div(Round::Up), "/^"),
+        Div(Round::Nearest) => (TokenType::Div(Round::Nearest), "/~"),
+        Mod => (TokenType::Mod, "%"),
+        Equal => (TokenType::Equal, "=="),
+        Less => (TokenType::Less, "<"),
+        Greater => (TokenType::Greater, ">"),
+        LessEq => (TokenType::LessEq, "<="),
+        GreaterEq => (TokenType::GreaterEq, ">="),
+        Range => (TokenType::Range, ".."),
+        Replace => (TokenType::Colon, ":"),
+        As => (TokenType::As, "as"),
+        Is(c) => (TokenType::Is(*c), is_word(*c)),
+        And => (TokenType::And, "and"),
+        Or => (TokenType::Or, "or"),
+        HighestN => (TokenType::HighestN, "h"),
+        LowestN => (TokenType::LowestN, "l"),
+        _ => return None,
+    };
+    Some(t)
+}
+
+fn compare(c: Compare) -> &'static str {
+    match c {
+        Compare::Equal => "==",
+        Compare::NotEqual => "!=",
+        Compare::Less => "<",
+        Compare::Greater => ">",
+        Compare::LessEq => "<=",
+        Compare::GreaterEq => ">=",
+    }
+}
+
+fn is_word(c: Compare) -> &'static str {
+    match c {
+        Compare::Equal => "eq",
+        Compare::NotEqual => "ne",
+        Compare::Less => "lt",
+        Compare::LessEq => "le",
+        Compare::Greater => "gt",
+        Compare::GreaterEq => "ge",
+    }
+}
+
+fn word(w: &str) -> String {
+    let plain = !w.is_empty() && w.chars().all(|c| c.is_alphabetic() || c == '_');
+    let tt = TokenType::from_word(w);
+    match plain && (tt == TokenType::Word(w) || tt.is_modifier()) {
+        true => w.to_string(),
+        false => format!("\"{}\"", w),
+    }
+}
+
+fn is_roll(a: &Ast) -> bool {
+    use Operation::*;
+    matches!(
+        a.kind,
+        AstKind::Op(
+            D | ZeroD
+                | Percentile
+                | Explode(_)
+                | Compound(_)
+                | Penetrate(_)
+                | RerollOnce(_)
+                | Reroll(_),
+            _
+        )
+    )
+}
+
+/// Text the parser reads back as a single value, as list items and triggers are
+fn operand(a: &Ast) -> anyhow::Result<Shown> {
+    let s = show(a)?;
+    Ok(match s.prec {
+        ATOM => s,
+        _ => s.paren(),
+    })
+}
+
+/// The left of a binary operation, which must bind at least as tightly
+fn left(a: &Ast, prec: i32) -> anyhow::Result<Shown> {
+    let s = show(a)?;
+    if s.prec < prec {
+        return Ok(s.paren());
+    }
+    Ok(s)
+}
+
+/// The right of a binary operation, which must bind more tightly
+fn right(a: &Ast, prec: i32) -> anyhow::Result<Shown> {
+    let s = show(a)?;
+    if s.prec <= prec {
+        return Ok(s.paren());
+    }
+    Ok(s)
+}
+
+/// The number of dice to roll, which must not read as a negated roll
+fn count(a: &Ast) -> anyhow::Result<Shown> {
+    let s = left(a, TokenType::D.precedence())?;
+    Ok(match s.s.starts_with('-') {
+        true => s.paren(),
+        false => s,
+    })
+}
+
+fn trigger(c: Compare, a: &Ast) -> anyhow::Result<Shown> {
+    let t = operand(a)?;
+    Ok(match (c, &a.kind) {
+        (Compare::Equal, AstKind::Op(Operation::Num(n), _)) if *n >= 0 => t,
+        _ => Shown::new(format!("{}{}", compare(c), t.s), ATOM, t.tail),
+    })
+}
+
+fn show(a: &Ast) -> anyhow::Result<Shown> {
+    use Operation::*;
+    let (op, args) = match &a.kind {
+        AstKind::Op(op, args) => (op, args),
+        AstKind::Def(name, params, body) => {
+            let params: Vec<String> = params.iter().map(|p| word(p)).collect();
+            let s = format!(
+                "fn {}({}) = {}",
+                word(name),
+                params.join(", "),
+                show(body)?.s
+            );
+            // the body takes everything after it
+            return Ok(Shown::new(s, 0, Tail::Closed));
+        }
+        AstKind::Let(name, vb) => {
+            let [value, body] = &**vb;
+            let s = format!(
+                "let {} = {} in {}",
+                word(name),
+                show(value)?.s,
+                show(body)?.s
+            );
+            return Ok(Shown::new(s, 0, Tail::Closed));
+        }
+        AstKind::If(parts) => {
+            let [cond, a, b] = &**parts;
+            let s = format!(
+                "if {} then {} else {}",
+                show(cond)?.s,
+                show(a)?.s,
+                show(b)?.s
+            );
+            return Ok(Shown::new(s, 0, Tail::Closed));
+        }
+        AstKind::Repeat(cb) => {
+            let [count, body] = &**cb;
+            let p = TokenType::Repeat.precedence();
+            let body = right(body, p)?;
+            let s = format!("repeat {} {}", operand(count)?.s, body.s);
+            return Ok(Shown::new(s, p, body.tail));
+        }
+        AstKind::Block(stmts) => {
+            let stmts: Vec<String> = stmts
+                .iter()
+                .map(|s| Ok(show(s)?.s))
+                .collect::<anyhow::Result<_>>()?;
+            return Ok(Shown::new(
+                format!("{{{}}}", stmts.join("; ")),
+                ATOM,
+                Tail::Closed,
+            ));
+        }
+    };
+    let dp = TokenType::D.precedence();
+    if let (Some((tt, sym)), [l, r]) = (infix(op), &args[..]) {
+        let p = tt.precedence();
+        let mut l = left(l, p)?;
+        let r = right(r, p)?;
+        if l.tail == Tail::Trigger && matches!(op, Equal | Less | Greater | LessEq | GreaterEq) {
+            l = l.paren();
+        }
+        let s = match op {
+            Range => join(&join(&l.s, sym), &r.s),
+            _ => format!("{} {} {}", l.s, sym, r.s),
+        };
+        return Ok(Shown::new(s, p, r.tail));
+    }
+    let s = match (op, &args[..]) {
+        (Num(n), _) => Shown::new(n.to_string(), ATOM, Tail::Closed),
+        (Word(w), _) => Shown::new(word(w), ATOM, Tail::Closed),
+        (L, _) => Shown::new("L".to_string(), ATOM, Tail::Closed),
+        (H, _) => Shown::new("H".to_string(), ATOM, Tail::Closed),
+        (P, _) => Shown::new("P".to_string(), ATOM, Tail::Closed),
+        (Fudge, _) => Shown::new("F".to_string(), ATOM, Tail::Closed),
+        (Bool(b), _) => Shown::new(b.to_string(), ATOM, Tail::Closed),
+        (Not, [v]) => {
+            let p = TokenType::Not.precedence();
+            let v = right(v, p)?;
+            Shown::new(format!("not {}", v.s), p, v.tail)
+        }
+        (List(_), items) => {
+            let items: Vec<String> = items
+                .iter()
+                .map(|i| Ok(operand(i)?.s))
+                .collect::<anyhow::Result<_>>()?;
+            Shown::new(format!("[{}]", items.join(", ")), ATOM, Tail::Closed)
+        }
+        (Call(name, _), args) => {
+            let args: Vec<String> = args
+                .iter()
+                .map(|a| Ok(show(a)?.s))
+                .collect::<anyhow::Result<_>>()?;
+            Shown::new(
+                format!("{}({})", word(name), args.join(", ")),
+                ATOM,
+                Tail::Closed,
+            )
+        }
+        (Sum, [v]) => Shown::new(format!("sum({})", show(v)?.s), ATOM, Tail::Closed),
+        (Var, [w]) => {
+            let w = operand(w)?;
+            Shown::new(join("$", &w.s), ATOM, w.tail)
+        }
+        (Neg, [v]) => {
+            // "-2d6" negates the roll, but "-2" alone is a negative number
+            let bare = match &v.kind {
+                AstKind::Op(Num(_), _) => false,
+                AstKind::Op(_, args) if is_roll(v) => {
+                    matches!(args.first().map(|a| &a.kind), Some(AstKind::Op(Num(n), _)) if *n >= 0)
+                }
+                _ => true,
+            };
+            let v = match bare {
+                true if is_roll(v) => show(v)?,
+                true => operand(v)?,
+                false => show(v)?.paren(),
+            };
+            Shown::new(format!("-{}", v.s), ATOM, v.tail)
+        }
+        (Count, [v]) => {
+            let mut v = left(v, TokenType::Count.precedence())?;
+            if v.tail != Tail::Closed {
+                v = v.paren();
+            }
+            Shown::new(
+                format!("{}!", v.s),
+                TokenType::Count.precedence(),
+                Tail::Bang,
+            )
+        }
+        (Successes(hit, double, fail), [pool, rest @ ..]) => {
+            let p = TokenType::Successes.precedence();
+            let mut s = format!("{} s ", left(pool, p)?.s);
+            let parts = [("", Some(*hit)), (" dbl ", *double), (" f ", *fail)];
+            let parts = parts.iter().filter_map(|(sep, c)| Some((sep, (*c)?)));
+            for ((sep, c), t) in parts.zip(rest) {
+                s.push_str(sep);
+                s.push_str(&trigger(c, t)?.s);
+            }
+            Shown::new(s, p, Tail::Closed)
+        }
+        (ZeroD, [n, die]) => {
+            let s = join(&join(&count(n)?.s, "z"), &right(die, dp)?.s);
+            Shown::new(s, dp, Tail::Closed)
+        }
+        (Percentile, [n]) => Shown::new(join(&count(n)?.s, "d%"), dp, Tail::Closed),
+        (_, [n, die, t @ ..]) if is_roll(a) => {
+            let count = count(n)?;
+            let die = right(die, dp)?;
+            let s = join(&join(&count.s, "d"), &die.s);
+            let (m, c) = match op {
+                Explode(c) => ("!", *c),
+                Compound(c) => ("!!", *c),
+                Penetrate(c) => ("!p", *c),
+                RerollOnce(c) => ("r", Some(*c)),
+                Reroll(c) => ("rr", Some(*c)),
+                _ => return Ok(Shown::new(s, dp, Tail::Dice)),
+            };
+            let s = format!("{}{}", s, m);
+            match (c, t) {
+                (Some(c), [t]) => {
+                    let t = trigger(c, t)?;
+                    Shown::new(join(&s, &t.s), dp, t.tail)
+                }
+                _ => Shown::new(s, dp, Tail::Trigger),
+            }
+        }
+        // the parser never builds these ops with other arguments, and the
+        // rest only come from their own AstKind, but a tree built by hand might
+        (
+            Var | Add | Append | Sub | Mul | Div(_) | Mod | Neg | D | ZeroD | Percentile | Sum
+            | Equal | Less | Greater | LessEq | GreaterEq | Range | Replace | Count | As | HighestN
+            | LowestN | Explode(_) | Compound(_) | Penetrate(_) | RerollOnce(_) | Reroll(_)
+            | Successes(..) | Def(_) | Let(_) | Scope | EndScope | Is(_) | And | Or | Not | Jump(_)
+            | JumpUnless(_) | Repeat(_),
+            _,
+        ) => {
+            return e_string(format!(
+                "Cannot format {:?} with {} arguments",
+                op,
+                args.len()
+            ))
+        }
+    };
+    Ok(s)
+}
+
+impl Ast {
+    /// The canonical text of the tree, erroring if it was built by hand
+    /// in a shape the parser never produces
+    pub fn canonical(&self) -> anyhow::Result<String> {
+        Ok(show(self)?.s)
+    }
+}
+
+/// Formats as the canonical text, failing with fmt::Error where `canonical` would error
+impl Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", show(self).map_err(|_| fmt::Error)?.s)
+    }
+}
+
+#[cfg(test)]
+mod format_test {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn canon(s: &str) -> String {
+        let f = format(s).unwrap();
+        assert_eq!(
+            parse_program(&f).unwrap(),
+            parse_program(s).unwrap(),
+            "{}",
+            f
+        );
+        assert_eq!(format(&f).unwrap(), f);
+        f.trim_end().to_string()
+    }
+
+    #[test]
+    pub fn test_canonical() {
+        assert_eq!(canon("4d6 h3"), "4d6 h 3");
+        assert_eq!(canon("4D6K3"), "4d6 h 3");
+        assert_eq!(canon("4 d 6 h 3"), "4d6 h 3");
+        assert_eq!(canon("(4d6 h 3) + 2"), "4d6 h 3 + 2");
+        assert_eq!(canon("4d6 h (3 + 2)"), "4d6 h (3 + 2)");
+        assert_eq!(canon("(5d10 s 8) * 2"), "5d10 s 8 * 2");
+        assert_eq!(canon("(1+2)*3"), "(1 + 2) * 3");
+        assert_eq!(canon("((1*2))+3"), "1 * 2 + 3");
+        assert_eq!(canon("1 - (2 - 3)"), "1 - (2 - 3)");
+        assert_eq!(canon("d20+-2"), "1d20 + -2");
+        assert_eq!(canon("-2147483648"), "-2147483648");
+        assert_eq!(canon("-2d6;-(3);- -3"), "-2d6\n-(3)\n-(-3)");
+        assert_eq!(canon("3d6 e >=5"), "3d6!>=5");
+        assert_eq!(canon("(3d6 !) > 3"), "(3d6!) > 3");
+        assert_eq!(canon("(1d6)!"), "(1d6)!");
+        assert_eq!(canon("5d10 s>=8 f1"), "5d10 s >=8 f 1");
+        assert_eq!(canon("4 d F"), "4d F");
+        assert_eq!(canon("2d10z + z6 - -2D%"), "2z10 + 1z6 - -2d%");
+        assert_eq!(canon("{\n1 as a\n\n$a;}"), "{1 as a; $a}");
+        assert_eq!(canon("not(1 eq 2)or(1 lt 2)"), "not 1 eq 2 or 1 lt 2");
+        assert_eq!(canon("fn f(e)=$e;f(1)"), "fn f(e) = $e\nf(1)");
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        for s in [
+            "3d6 as str; $str + 2",
+            "[1, -2, d6, \"a b\"] ++ [3]",
+            "2d6!! + 2d6!p>4 + 4d6r<3 + 4d6rr1 - 1d-3..3",
+            "10d10 s >=8 dbl 10 f 1",
+            "-(d6) * 3 /^ 2 /~ 4 % 5 : 7",
+            "(1 + 3d6)! + 2d(1d6) + (2d6)d4 + 3d(6!)",
+            "[6, 4, 2] l 2 h 1 > 2 < 3 <= 4 >= 5 == 6",
+            "L + H + P + $\"as\"",
+            "fn adv(x) = 2d20 h 1 + $x\nadv(5) + adv(1d4 + 1)",
+            "fn zero() = 0;(fn one(a, b) = 1) + zero()",
+            "let x = 3d6 in $x + $x",
+            "1 + (let x = let y = 2 in $y in $x) * 2",
+            "{1d6 as a; $a * 2} + {(3 as b)\n$b}",
+            "if 1d20 + 5 ge 15 then 2d6 else 0",
+            "repeat 6 4d6 h 3 as stats; (repeat $n {1d6 as a; $a * 2}) h 1 ++ [1]",
+            "not $a and ($b or true) ne false; 1 + (if true then 1 else 2)",
+            "sum(3d6 h 2) + sum(1, 2) + map([1], sum)",
+        ] {
+            canon(s);
+        }
+    }
+
+    #[test]
+    pub fn test_built_tree() {
+        let sp = crate::error::Span::new(0, 0);
+        let add = Ast::op(Operation::Add, vec![], sp);
+        assert_eq!(
+            add.canonical().unwrap_err().to_string(),
+            "Cannot format Add with 0 arguments"
+        );
+        assert!(fmt::write(&mut String::new(), format_args!("{}", add)).is_err());
+        let roll = Ast::op(Operation::D, vec![], sp);
+        assert!(Ast::op(Operation::Neg, vec![roll], sp).canonical().is_err());
+    }
+}
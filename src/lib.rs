@@ -0,0 +1,25 @@
+//! A language for rolling dice, eg "4d6 h 3 + 2".
+//!
+//! `roll` evaluates a single program with fresh state, while a `Roller`
+//! keeps its variables and rng between rolls.
+
+pub mod ast;
+pub mod builtin;
+pub mod context;
+pub mod dice;
+pub mod dist;
+pub mod error;
+pub mod explain;
+pub mod expr;
+pub mod format;
+pub mod limits;
+pub mod parser;
+pub mod roller;
+pub mod sim;
+pub mod tokenizer;
+
+pub use context::Context;
+pub use dice::Value;
+pub use expr::Expr;
+pub use limits::Limits;
+pub use roller::{roll, Outcome, Roller};
@@ -0,0 +1,161 @@
+use crate::error::{DiceError, ErrorKind};
+use std::fmt::{self, Display};
+
+/// Bounds on the work a single expression may do,
+/// so a roll like 1000000000d6 fails rather than exhausting memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub dice_per_roll: usize,
+    pub dice_total: usize,
+    pub list_len: usize,
+    pub stack_depth: usize,
+    pub rerolls: usize, //Per die, counting explosions too
+    pub steps: usize,
+    pub call_depth: usize,
+    pub nesting: usize, //Checked while parsing
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            dice_per_roll: 10_000,
+            dice_total: 100_000,
+            list_len: 100_000,
+            stack_depth: 1_000,
+            rerolls: 1_000,
+            steps: 1_000_000,
+            call_depth: 64,
+            nesting: 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    DicePerRoll,
+    DiceTotal,
+    ListLen,
+    StackDepth,
+    Rerolls,
+    Steps,
+    CallDepth,
+    Nesting,
+}
+
+impl Limits {
+    pub fn get(&self, l: Limit) -> usize {
+        match l {
+            Limit::DicePerRoll => self.dice_per_roll,
+            Limit::DiceTotal => self.dice_total,
+            Limit::ListLen => self.list_len,
+            Limit::StackDepth => self.stack_depth,
+            Limit::Rerolls => self.rerolls,
+            Limit::Steps => self.steps,
+            Limit::CallDepth => self.call_depth,
+            Limit::Nesting => self.nesting,
+        }
+    }
+
+    /// Errors if n goes beyond the limit
+    pub fn check(&self, l: Limit, n: usize) -> anyhow::Result<()> {
+        let max = self.get(l);
+        if n > max {
+            return Err(DiceError::new(ErrorKind::LimitExceeded(l, max), None).into());
+        }
+        Ok(())
+    }
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::DicePerRoll => "dice in one roll",
+            Self::DiceTotal => "dice in one expression",
+            Self::ListLen => "items in a list",
+            Self::StackDepth => "values on the stack",
+            Self::Rerolls => "rerolls of one die",
+            Self::Steps => "evaluation steps",
+            Self::CallDepth => "nested function calls",
+            Self::Nesting => "levels of nesting",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[cfg(test)]
+mod limits_test {
+    use super::*;
+    use crate::context::Context;
+    use crate::parser::parse_expr;
+
+    fn exceeded(s: &str, limits: Limits) -> Option<Limit> {
+        let mut ct = Context::seeded(1);
+        ct.set_limits(limits);
+        let e = parse_expr(s).unwrap().resolve(&mut ct).err()?;
+        match e.downcast::<DiceError>().ok()?.kind {
+            ErrorKind::LimitExceeded(l, _) => Some(l),
+            _ => None,
+        }
+    }
+
+    #[test]
+    pub fn test_limits() {
+        let d = Limits::default();
+        assert_eq!(exceeded("1000000000d6", d), Some(Limit::DicePerRoll));
+        assert_eq!(
+            exceeded(
+                "9000d6 + 9000d6 + 9000d6",
+                Limits {
+                    dice_total: 20_000,
+                    ..d
+                }
+            ),
+            Some(Limit::DiceTotal)
+        );
+        assert_eq!(
+            exceeded("[1, 2, 3] ++ [4]", Limits { list_len: 3, ..d }),
+            Some(Limit::ListLen)
+        );
+        assert_eq!(exceeded("10d1!", d), Some(Limit::Rerolls));
+        assert_eq!(exceeded("10d2 rr <3", d), Some(Limit::Rerolls));
+        assert_eq!(
+            exceeded("1 + 2 + 3", Limits { steps: 4, ..d }),
+            Some(Limit::Steps)
+        );
+        assert_eq!(
+            exceeded(
+                "1 + (2 + (3 + 4))",
+                Limits {
+                    stack_depth: 2,
+                    ..d
+                }
+            ),
+            Some(Limit::StackDepth)
+        );
+        let stack = |n| Limits {
+            stack_depth: n,
+            ..d
+        };
+        assert_eq!(exceeded("1 + (2 + 3)", stack(3)), None);
+        assert_eq!(exceeded("1 + (2 + 3)", stack(2)), Some(Limit::StackDepth));
+        assert_eq!(exceeded("1 + 2 + 3", Limits { steps: 5, ..d }), None);
+        assert_eq!(
+            exceeded("repeat 4 1", Limits { list_len: 3, ..d }),
+            Some(Limit::ListLen)
+        );
+        assert_eq!(
+            exceeded("repeat 100 repeat 100 1", Limits { steps: 5000, ..d }),
+            Some(Limit::Steps)
+        );
+
+        let e = parse_expr("2 + 1000000000d6")
+            .unwrap()
+            .resolve(&mut Context::new())
+            .unwrap_err();
+        let e = crate::error::in_source(e, "2 + 1000000000d6");
+        assert_eq!(
+            e.to_string(),
+            "Limit exceeded: more than 10000 dice in one roll\n   1 | 2 + 1000000000d6\n     |               ^"
+        );
+    }
+}
@@ -1,20 +1,120 @@
-pub mod context;
-pub mod dice;
-pub mod expr;
-//pub mod instruction; //TODO remove
-pub mod parser;
-pub mod tokenizer;
-//use expr::*;
+mod repl;
+use dice_lang::{context, error, format, parser, sim};
+use err_tools::*;
 
 fn main() -> anyhow::Result<()> {
-    for (i, a) in std::env::args().skip(1).enumerate() {
-        println!("Roll {} : {}\n", i, a);
-        let j = parser::parse_expr(&a)?;
-        println!("   expr = {:?}\n\n", j.ops);
-
-        let mut ct = context::Context::new();
-        let dr = j.resolve(&mut ct)?;
-        println!("{}\nResult = {}", ct, dr);
+    let mut dist = false;
+    let mut json = false;
+    let mut simulate: Option<usize> = None;
+    let mut seed: Option<u64> = None;
+    let mut exprs = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--dist" => dist = true,
+            "--json" => json = true,
+            "--simulate" => {
+                let n = args.next().e_str("--simulate needs a number of runs")?;
+                simulate = Some(n.parse()?);
+            }
+            "--seed" => {
+                let n = args.next().e_str("--seed needs a number")?;
+                seed = Some(n.parse()?);
+            }
+            _ => exprs.push(a),
+        }
+    }
+
+    if exprs.first().map(String::as_str) == Some("run") {
+        let path = exprs.get(1).e_str("run needs a file")?;
+        let src = std::fs::read_to_string(path)?;
+        let mut ct = match seed {
+            Some(s) => context::Context::seeded(s),
+            None => context::Context::new(),
+        };
+        let run = |ct: &mut context::Context| -> anyhow::Result<()> {
+            for a in parser::parse_program_ast(&src)? {
+                // each statement reports only its own rolls
+                let first = ct.rolls().len();
+                let (dr, ex) = a.compile().explain(ct)?;
+                match json {
+                    true => println!(
+                        "{}",
+                        serde_json::to_string(&ct.report(first, &a.canonical()?, &dr))?
+                    ),
+                    false => println!("{}", ex),
+                }
+            }
+            Ok(())
+        };
+        return run(&mut ct).map_err(|e| error::in_source(e, &src));
+    }
+
+    if exprs.first().map(String::as_str) == Some("fmt") {
+        let mut srcs = exprs[1..].to_vec();
+        if srcs.is_empty() {
+            srcs.push(std::io::read_to_string(std::io::stdin())?);
+        }
+        for src in srcs {
+            print!(
+                "{}",
+                format::format(&src).map_err(|e| error::in_source(e, &src))?
+            );
+        }
+        return Ok(());
+    }
+
+    if exprs.is_empty() {
+        let mut ct = match seed {
+            Some(s) => context::Context::seeded(s),
+            None => context::Context::new(),
+        };
+        return repl::run(&mut ct);
+    }
+
+    for (i, a) in exprs.iter().enumerate() {
+        let j = parser::parse_expr(a).map_err(|e| error::in_source(e, a))?;
+        if !json {
+            println!("Roll {} : {}\n", i, a);
+            println!("   expr = {:?}\n\n", j.ops);
+        }
+
+        if dist {
+            let d = j.dist()?;
+            for (n, p) in d.numbers()? {
+                println!(
+                    "{} : {:.4}%  (>= {:.4}%)",
+                    n,
+                    p * 100.,
+                    d.at_least(n)? * 100.
+                );
+            }
+            println!(
+                "Mean = {:.4}, Variance = {:.4}, Min = {}, Max = {}",
+                d.mean()?,
+                d.variance()?,
+                d.min()?,
+                d.max()?
+            );
+            continue;
+        }
+
+        if let Some(runs) = simulate {
+            let seed = seed.unwrap_or_else(rand::random);
+            println!("Seed = {}", seed);
+            println!("{}", sim::simulate(&j, runs, seed)?);
+            continue;
+        }
+
+        let mut ct = match seed {
+            Some(s) => context::Context::seeded(s.wrapping_add(i as u64)),
+            None => context::Context::new(),
+        };
+        let (dr, ex) = j.explain(&mut ct).map_err(|e| error::in_source(e, a))?;
+        match json {
+            true => println!("{}", serde_json::to_string(&ct.report(0, a, &dr))?),
+            false => println!("{}\n{}Result = {}", ex, ct, dr),
+        }
     }
     Ok(())
 }
@@ -1,25 +1,67 @@
+use crate::ast::{Ast, AstKind};
+use crate::builtin::BUILTINS;
+use crate::dice::Compare;
+use crate::error::{DiceError, ErrorKind, Span};
 use crate::expr::*;
+use crate::limits::{Limit, Limits};
 use crate::tokenizer::{Token, TokenRes, TokenType, Tokenizer};
 use err_tools::*;
 
 macro_rules! bin_op {
-    ($s:ident,$x:ident,$p:ident) => {{
+    ($s:ident,$x:ident,$p:ident,$sp:ident) => {{
         $s.peek = None;
         $s.expr($p)?;
-        $s.emit(Operation::$x);
+        $s.emit(Operation::$x, $sp);
     }};
 }
 
 pub fn parse_expr(s: &str) -> anyhow::Result<Expr> {
+    Ok(parse_ast(s)?.compile())
+}
+
+/// Parse statements separated by ';' or newlines, each into its own Expr
+pub fn parse_program(s: &str) -> anyhow::Result<Vec<Expr>> {
+    Ok(parse_program_ast(s)?.iter().map(Ast::compile).collect())
+}
+
+pub fn parse_ast(s: &str) -> anyhow::Result<Ast> {
     let mut p = Parser::new(s);
     p.expr(0)?;
-    Ok(p.target)
+    p.end_statement()?;
+    match p.peek_token()? {
+        None => p.finish(),
+        Some(_) => p.unexpected("the end of the expression"),
+    }
+}
+
+pub fn parse_program_ast(s: &str) -> anyhow::Result<Vec<Ast>> {
+    let mut p = Parser::new(s);
+    let mut res = Vec::new();
+    p.end_statement()?;
+    while p.peek_token()?.is_some() {
+        p.expr(0)?;
+        match p.peek_type()? {
+            None | Some(TokenType::Semi) => {}
+            Some(_) => return p.unexpected("the end of the statement"),
+        }
+        p.end_statement()?;
+        res.push(p.finish()?);
+    }
+    Ok(res)
+}
+
+/// A number literal as an i32, which only -2147483648 needs the sign to fit
+fn num(n: i64, sp: Span) -> anyhow::Result<i32> {
+    i32::try_from(n).map_err(|_| DiceError::new(ErrorKind::Overflow, Some(sp)).into())
 }
 
 pub struct Parser<'a> {
     t: Tokenizer<'a>,
     peek: Option<Token<'a>>,
-    target: Expr,
+    nodes: Vec<Ast>,
+    len: usize,
+    depth: usize,
+    limits: Limits,
 }
 
 impl<'a> Parser<'a> {
@@ -27,11 +69,34 @@ impl<'a> Parser<'a> {
         Parser {
             t: Tokenizer::new(s),
             peek: None,
-            target: Expr::new(),
+            nodes: Vec::new(),
+            len: s.len(),
+            depth: 0,
+            limits: Limits::default(),
         }
     }
-    pub fn emit(&mut self, op: Operation) {
-        self.target.ops.push(op);
+
+    /// Limit how deeply the input may nest, so it can't overflow the parser's own stack
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Build a node for the op from the nodes its arguments were parsed into
+    pub fn emit(&mut self, op: Operation, sp: Span) {
+        let l = self.nodes.len().saturating_sub(op.arity());
+        let args = self.nodes.split_off(l);
+        self.nodes.push(Ast::op(op, args, sp));
+    }
+
+    fn finish_node(&mut self) -> anyhow::Result<Ast> {
+        self.nodes.pop().e_str("Expected an expression")
+    }
+
+    /// Take the tree of the statement just parsed
+    pub fn finish(&mut self) -> anyhow::Result<Ast> {
+        match (self.nodes.pop(), self.nodes.is_empty()) {
+            (Some(a), true) => Ok(a),
+            _ => e_str("Statement did not parse to a single expression"),
+        }
     }
 
     pub fn next_token(&mut self) -> TokenRes<'a> {
@@ -51,26 +116,50 @@ impl<'a> Parser<'a> {
         }
     }
 
-    pub fn peek_type(&mut self) -> Option<TokenType> {
+    pub fn peek_type(&mut self) -> anyhow::Result<Option<TokenType<'a>>> {
+        Ok(self.peek_token()?.map(|t| t.tt))
+    }
+
+    /// The span of the next token, or the end of input
+    pub fn peek_span(&mut self) -> Span {
         match self.peek_token() {
-            Err(_) => None,
-            Ok(Some(t)) => Some(t.tt.clone()),
-            Ok(None) => None,
+            Ok(Some(t)) => t.span(),
+            _ => Span::new(self.len, self.len),
         }
     }
 
-    pub fn consume_token(&mut self, tt: TokenType<'a>) -> anyhow::Result<()> {
-        let t = self.peek_type().e_str("Tried to consume EOI")?;
-        if t == tt {
+    /// An error describing what was expected instead of the next token
+    pub fn unexpected<T>(&mut self, expected: &str) -> anyhow::Result<T> {
+        let found = match self.peek_token()? {
+            Some(t) => format!("'{}'", t.s.trim()),
+            None => "the end of input".to_string(),
+        };
+        Err(DiceError::unexpected(expected, found, self.peek_span()).into())
+    }
+
+    pub fn consume_token(&mut self, tt: TokenType<'a>, expected: &str) -> anyhow::Result<()> {
+        if self.peek_type()? == Some(tt) {
             self.peek = None;
             return Ok(());
         }
-        return e_str("Consume token, required token did not match");
+        self.unexpected(expected)
+    }
+
+    /// Skip any statement separators
+    pub fn end_statement(&mut self) -> anyhow::Result<()> {
+        while let Some(Token {
+            tt: TokenType::Semi,
+            ..
+        }) = self.peek_token()?
+        {
+            self.peek = None;
+        }
+        Ok(())
     }
 
     pub fn expr(&mut self, prec: i32) -> anyhow::Result<()> {
         self.unary()?;
-        while let Some(t) = self.peek_type() {
+        while let Some(t) = self.peek_type()? {
             if t == TokenType::BraceC {
                 return Ok(());
             }
@@ -79,88 +168,403 @@ impl<'a> Parser<'a> {
                 return Ok(());
             }
             self.binary(tp)?;
+            self.check_depth()?;
+        }
+        Ok(())
+    }
+
+    /// A long chain like 1 + 1 + ... parses in a loop, but still builds a
+    /// deep tree, so check it before anything walks the tree recursively
+    fn check_depth(&self) -> anyhow::Result<()> {
+        if let Some(a) = self.nodes.last() {
+            self.limits
+                .check(Limit::Nesting, a.depth())
+                .map_err(|e| DiceError::locate(e, Some(a.span)))?;
         }
         Ok(())
     }
 
+    /// Parse a single value, which may nest further values inside it
     pub fn unary(&mut self) -> anyhow::Result<()> {
+        self.depth += 1;
+        let sp = self.peek_span();
+        self.limits
+            .check(Limit::Nesting, self.depth)
+            .map_err(|e| DiceError::locate(e, Some(sp)))?;
+        let res = self.value();
+        self.depth -= 1;
+        res
+    }
+
+    fn value(&mut self) -> anyhow::Result<()> {
+        if self.peek_token()?.is_none() {
+            return self.unexpected("a value");
+        }
+        let sp = self.peek_span();
         let t = self.next_token()?.e_str("Expected Value found EOI")?;
+        if let Some(w) = t.name() {
+            let ws = w.to_string();
+            match self.peek_type()? {
+                Some(TokenType::ParenO) => self.call(ws, sp)?,
+                _ => self.emit(Operation::Word(ws), sp),
+            }
+            return Ok(());
+        }
         match t.tt {
-            TokenType::Number(n) => self.emit(Operation::Num(n)),
-            TokenType::Word(w) => {
-                let ws = w.to_string();
-                self.emit(Operation::Word(ws));
-            }
-            TokenType::P => self.emit(Operation::P),
-            TokenType::F => self.emit(Operation::Fudge),
-            TokenType::L => self.emit(Operation::L),
-            TokenType::H => self.emit(Operation::H),
+            TokenType::Number(n) => self.emit(Operation::Num(num(n, sp)?), sp),
+            TokenType::Fn => self.def(sp)?,
+            TokenType::Let => self.let_in(sp)?,
+            TokenType::CurlyO => self.block(sp)?,
+            TokenType::If => self.if_else(sp)?,
+            TokenType::Repeat => self.repeat(sp)?,
+            TokenType::Bool(b) => self.emit(Operation::Bool(b), sp),
+            TokenType::Not => {
+                self.expr(TokenType::Not.precedence())?;
+                self.emit(Operation::Not, sp);
+            }
+            TokenType::Sub => match self.peek_type()? {
+                Some(TokenType::Number(n)) => {
+                    let nsp = self.peek_span();
+                    self.peek = None;
+                    // A negated roll count negates the whole roll
+                    let tt = self.peek_type()?;
+                    if let Some(tt @ (TokenType::D | TokenType::Z | TokenType::Percentile)) = tt {
+                        let dsp = self.peek_span();
+                        self.peek = None;
+                        self.emit(Operation::Num(num(n, nsp)?), nsp);
+                        self.dice_kind(tt, dsp)?;
+                        self.emit(Operation::Neg, sp);
+                    } else {
+                        let sp = Span::new(sp.start, nsp.end);
+                        self.emit(Operation::Num(num(-n, sp)?), sp);
+                    }
+                }
+                _ => {
+                    self.unary()?;
+                    self.emit(Operation::Neg, sp);
+                }
+            },
+            TokenType::P => self.emit(Operation::P, sp),
+            TokenType::F => self.emit(Operation::Fudge, sp),
+            TokenType::L => self.emit(Operation::L, sp),
+            TokenType::H => self.emit(Operation::H, sp),
             TokenType::Dollar => {
+                let wsp = self.peek_span();
                 self.unary()?;
-                self.emit(Operation::Var);
+                self.emit(Operation::Var, Span::new(sp.start, wsp.end));
             }
-            TokenType::D => {
-                self.emit(Operation::Num(1));
-                self.expr(TokenType::D.precedence())?;
-                self.emit(Operation::D);
+            tt @ (TokenType::D | TokenType::Z | TokenType::Percentile) => {
+                self.emit(Operation::Num(1), sp);
+                self.dice_kind(tt, sp)?;
             }
             TokenType::BraceO => {
-                self.list()?;
+                self.list(sp)?;
             }
             TokenType::ParenO => {
-                self.peek = None;
                 self.expr(0)?;
-                self.consume_token(TokenType::ParenC)?;
+                self.consume_token(TokenType::ParenC, "')'")?;
+            }
+            _ => {
+                self.peek = Some(t);
+                return self.unexpected("a value");
             }
-            t => return e_string(format!("Expected **Unary** operation found '{:?}'", t)),
         }
         Ok(())
     }
 
     pub fn binary(&mut self, prec: i32) -> anyhow::Result<()> {
-        let t = self.peek_type().e_str("Expected Token found EOI")?;
+        let t = self.peek_type()?.e_str("Expected Token found EOI")?;
+        let sp = self.peek_span();
         let tp = t.precedence();
         if tp < prec {
             return Ok(());
         }
         match t {
-            TokenType::BraceC | TokenType::ParenC => {
-                println!("found ender : {:?}  ", t);
-                return Ok(());
-            }
+            TokenType::BraceC | TokenType::ParenC => return Ok(()),
             TokenType::Count => {
                 self.peek = None;
-                self.emit(Operation::Count);
-            }
-            TokenType::Colon => bin_op!(self, Replace, tp),
-            TokenType::D => bin_op!(self, D, tp),
-            TokenType::Add => bin_op!(self, Add, tp),
-            TokenType::Sub => bin_op!(self, Sub, tp),
-            TokenType::Range => bin_op!(self, Range, tp),
-            TokenType::Equal => bin_op!(self, Equal, tp),
-            TokenType::Less => bin_op!(self, Less, tp),
-            TokenType::Greater => bin_op!(self, Greater, tp),
-            TokenType::As => bin_op!(self, As, tp),
-            TokenType::Append => bin_op!(self, Append, tp),
-            TokenType::LowestN => bin_op!(self, LowestN, tp),
-            TokenType::HighestN => bin_op!(self, HighestN, tp),
-            t => return e_string(format!("Expected **Binary** operation found '{:?}'", t)),
+                self.emit(Operation::Count, sp);
+            }
+            TokenType::Colon => bin_op!(self, Replace, tp, sp),
+            tt @ (TokenType::D | TokenType::Z | TokenType::Percentile) => {
+                self.peek = None;
+                self.dice_kind(tt, sp)?;
+            }
+            TokenType::Add => bin_op!(self, Add, tp, sp),
+            TokenType::Sub => bin_op!(self, Sub, tp, sp),
+            TokenType::Mul => bin_op!(self, Mul, tp, sp),
+            TokenType::Mod => bin_op!(self, Mod, tp, sp),
+            TokenType::Div(r) => {
+                self.peek = None;
+                self.expr(tp)?;
+                self.emit(Operation::Div(r), sp);
+            }
+            TokenType::Range => bin_op!(self, Range, tp, sp),
+            TokenType::Equal => bin_op!(self, Equal, tp, sp),
+            TokenType::Less => bin_op!(self, Less, tp, sp),
+            TokenType::Greater => bin_op!(self, Greater, tp, sp),
+            TokenType::LessEq => bin_op!(self, LessEq, tp, sp),
+            TokenType::GreaterEq => bin_op!(self, GreaterEq, tp, sp),
+            TokenType::As => bin_op!(self, As, tp, sp),
+            TokenType::Is(c) => {
+                self.peek = None;
+                self.expr(tp)?;
+                self.emit(Operation::Is(c), sp);
+            }
+            TokenType::And => bin_op!(self, And, tp, sp),
+            TokenType::Or => bin_op!(self, Or, tp, sp),
+            TokenType::Append => bin_op!(self, Append, tp, sp),
+            TokenType::LowestN => bin_op!(self, LowestN, tp, sp),
+            TokenType::HighestN => bin_op!(self, HighestN, tp, sp),
+            TokenType::Successes => {
+                self.peek = None;
+                let hit = self.required_trigger("a success target like >=8")?;
+                let double = self.part_trigger(TokenType::Double)?;
+                let fail = self.part_trigger(TokenType::Fail)?;
+                self.emit(Operation::Successes(hit, double, fail), sp);
+            }
+            _ => return self.unexpected("an operator"),
+        }
+        Ok(())
+    }
+
+    /// Parse the rest of a roll of standard, zero based or percentile dice.
+    /// The count must already be emitted.
+    pub fn dice_kind(&mut self, tt: TokenType<'a>, sp: Span) -> anyhow::Result<()> {
+        match tt {
+            TokenType::Z => {
+                self.expr(TokenType::Z.precedence())?;
+                self.emit(Operation::ZeroD, sp);
+            }
+            TokenType::Percentile => self.emit(Operation::Percentile, sp),
+            _ => self.dice(sp)?,
+        }
+        Ok(())
+    }
+
+    /// Parse the die of a roll and any modifier directly after it,
+    /// emitting the roll. The count must already be emitted.
+    pub fn dice(&mut self, sp: Span) -> anyhow::Result<()> {
+        self.expr(TokenType::D.precedence())?;
+        let msp = self.peek_span();
+        match self.peek_type()? {
+            Some(TokenType::Z) => {
+                self.peek = None;
+                self.emit(Operation::ZeroD, sp);
+            }
+            Some(TokenType::Count) | Some(TokenType::Explode) => {
+                self.peek = None;
+                if let Some(TokenType::Penetrate) = self.peek_type()? {
+                    self.peek = None;
+                    let c = self.trigger()?;
+                    self.emit(Operation::Penetrate(c), msp);
+                } else {
+                    let c = self.trigger()?;
+                    self.emit(Operation::Explode(c), msp);
+                }
+            }
+            Some(TokenType::Compound) => {
+                self.peek = None;
+                let c = self.trigger()?;
+                self.emit(Operation::Compound(c), msp);
+            }
+            Some(TokenType::RerollOnce) => {
+                self.peek = None;
+                let c = self.required_trigger("a reroll condition like <3")?;
+                self.emit(Operation::RerollOnce(c), msp);
+            }
+            Some(TokenType::Reroll) => {
+                self.peek = None;
+                let c = self.required_trigger("a reroll condition like <3")?;
+                self.emit(Operation::Reroll(c), msp);
+            }
+            _ => self.emit(Operation::D, sp),
         }
         Ok(())
     }
 
-    pub fn list(&mut self) -> anyhow::Result<()> {
-        self.peek = None; // TODO check if non null peek is BraceO
+    /// Parse an optional modifier trigger like ">=5", or a bare number to match exactly
+    pub fn trigger(&mut self) -> anyhow::Result<Option<Compare>> {
+        let (c, consume) = match self.peek_type()? {
+            Some(TokenType::Number(_)) => (Compare::Equal, false),
+            Some(TokenType::Equal) => (Compare::Equal, true),
+            Some(TokenType::Less) => (Compare::Less, true),
+            Some(TokenType::Greater) => (Compare::Greater, true),
+            Some(TokenType::LessEq) => (Compare::LessEq, true),
+            Some(TokenType::GreaterEq) => (Compare::GreaterEq, true),
+            _ => return Ok(None),
+        };
+        if consume {
+            self.peek = None;
+        }
+        self.unary()?;
+        Ok(Some(c))
+    }
+
+    pub fn required_trigger(&mut self, expected: &str) -> anyhow::Result<Compare> {
+        match self.trigger()? {
+            Some(c) => Ok(c),
+            None => self.unexpected(expected),
+        }
+    }
+
+    /// Parse a trigger introduced by the given token, if that token is next
+    pub fn part_trigger(&mut self, tt: TokenType<'a>) -> anyhow::Result<Option<Compare>> {
+        if self.peek_type()? != Some(tt) {
+            return Ok(None);
+        }
+        self.peek = None;
+        self.required_trigger("a condition").map(Some)
+    }
+
+    /// Parse the arguments of a call, the name already read
+    pub fn call(&mut self, name: String, sp: Span) -> anyhow::Result<()> {
+        self.consume_token(TokenType::ParenO, "'('")?;
+        let mut n = 0;
+        while self.peek_type()? != Some(TokenType::ParenC) {
+            if n > 0 {
+                self.consume_token(TokenType::Comma, "',' or ')'")?;
+            }
+            self.expr(0)?;
+            n += 1;
+        }
+        let esp = self.peek_span();
+        self.peek = None;
+        let sp = Span::new(sp.start, esp.end);
+        // sum has its own op, which exact distributions can work through
+        match (name.as_str(), n) {
+            ("sum", 1) => self.emit(Operation::Sum, sp),
+            _ => self.emit(Operation::Call(name, n), sp),
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, expected: &str) -> anyhow::Result<String> {
+        match self.peek_token()?.and_then(|t| t.name()) {
+            Some(w) => {
+                self.peek = None;
+                Ok(w.to_string())
+            }
+            None => self.unexpected(expected),
+        }
+    }
+
+    /// Parse "name(params) = body" after 'fn'
+    pub fn def(&mut self, sp: Span) -> anyhow::Result<()> {
+        let nsp = self.peek_span();
+        let name = self.word("a function name")?;
+        if BUILTINS.iter().any(|(b, _)| *b == name) {
+            let e = DiceError::message(format!("Cannot redefine the builtin '{}'", name), nsp);
+            return Err(e.into());
+        }
+        self.consume_token(TokenType::ParenO, "'('")?;
+        let mut params = Vec::new();
+        while self.peek_type()? != Some(TokenType::ParenC) {
+            if !params.is_empty() {
+                self.consume_token(TokenType::Comma, "',' or ')'")?;
+            }
+            let psp = self.peek_span();
+            let p = self.word("a parameter name")?;
+            if params.contains(&p) {
+                let e = DiceError::message(format!("Parameter '{}' is repeated", p), psp);
+                return Err(e.into());
+            }
+            params.push(p);
+        }
+        self.peek = None;
+        self.consume_token(TokenType::Assign, "'='")?;
+        self.expr(0)?;
+        let body = self.finish_node()?;
+        self.nodes.push(Ast {
+            kind: AstKind::Def(name, params, Box::new(body)),
+            span: sp,
+        });
+        Ok(())
+    }
+
+    /// Parse "name = value in body" after 'let'
+    pub fn let_in(&mut self, sp: Span) -> anyhow::Result<()> {
+        let name = self.word("a variable name")?;
+        self.consume_token(TokenType::Assign, "'='")?;
+        self.expr(0)?;
+        self.consume_token(TokenType::In, "'in'")?;
+        self.expr(0)?;
+        let body = self.finish_node()?;
+        let value = self.finish_node()?;
+        self.nodes.push(Ast {
+            kind: AstKind::Let(name, Box::new([value, body])),
+            span: sp,
+        });
+        Ok(())
+    }
+
+    /// Parse "cond then a else b" after 'if'
+    pub fn if_else(&mut self, sp: Span) -> anyhow::Result<()> {
+        self.expr(0)?;
+        self.consume_token(TokenType::Then, "'then'")?;
+        self.expr(0)?;
+        self.consume_token(TokenType::Else, "'else'")?;
+        self.expr(0)?;
+        let b = self.finish_node()?;
+        let a = self.finish_node()?;
+        let cond = self.finish_node()?;
+        self.nodes.push(Ast {
+            kind: AstKind::If(Box::new([cond, a, b])),
+            span: sp,
+        });
+        Ok(())
+    }
+
+    /// Parse "count body" after 'repeat', the count a single value
+    pub fn repeat(&mut self, sp: Span) -> anyhow::Result<()> {
+        self.unary()?;
+        self.expr(TokenType::Repeat.precedence())?;
+        let body = self.finish_node()?;
+        let count = self.finish_node()?;
+        self.nodes.push(Ast {
+            kind: AstKind::Repeat(Box::new([count, body])),
+            span: sp,
+        });
+        Ok(())
+    }
+
+    /// Parse statements up to the closing '}', the '{' already read
+    pub fn block(&mut self, sp: Span) -> anyhow::Result<()> {
+        let mut stmts = Vec::new();
+        self.end_statement()?;
+        while self.peek_type()? != Some(TokenType::CurlyC) {
+            self.expr(0)?;
+            stmts.push(self.finish_node()?);
+            match self.peek_type()? {
+                Some(TokenType::Semi | TokenType::CurlyC) => {}
+                _ => return self.unexpected("';' or '}'"),
+            }
+            self.end_statement()?;
+        }
+        if stmts.is_empty() {
+            return self.unexpected("a statement");
+        }
+        let esp = self.peek_span();
+        self.peek = None;
+        self.nodes.push(Ast {
+            kind: AstKind::Block(stmts),
+            span: Span::new(sp.start, esp.end),
+        });
+        Ok(())
+    }
+
+    pub fn list(&mut self, sp: Span) -> anyhow::Result<()> {
         let mut n = 0;
         loop {
-            println!("List Loop");
-            match self.peek_type().e_str("Unclosed List")? {
-                TokenType::BraceC => {
+            match self.peek_type()? {
+                None => return self.unexpected("']' to close the list"),
+                Some(TokenType::BraceC) => {
+                    let esp = self.peek_span();
                     self.peek = None;
-                    self.emit(Operation::List(n));
+                    self.emit(Operation::List(n), Span::new(sp.start, esp.end));
                     return Ok(());
                 }
-                TokenType::Comma => {
+                Some(TokenType::Comma) => {
                     self.peek = None;
                     self.unary()?;
                     n += 1;
@@ -173,3 +577,103 @@ impl<'a> Parser<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod parser_test {
+    use super::*;
+    use Operation::*;
+
+    fn ops(s: &str) -> Vec<Operation> {
+        parse_expr(s).unwrap().ops
+    }
+
+    #[test]
+    pub fn test_negation() {
+        assert_eq!(ops("-3"), vec![Num(-3)]);
+        assert_eq!(ops("1d20 + -2"), vec![Num(1), Num(20), D, Num(-2), Add]);
+        assert_eq!(ops("-3..3"), vec![Num(-3), Num(3), Range]);
+        assert_eq!(ops("-3..-1"), vec![Num(-3), Num(-1), Range]);
+        assert_eq!(
+            ops("[-1, 0, 1]"),
+            vec![Num(-1), Num(0), Num(1), Operation::List(3)]
+        );
+        assert_eq!(ops("-2d6"), vec![Num(2), Num(6), D, Neg]);
+        assert_eq!(ops("-2d6 + 1"), vec![Num(2), Num(6), D, Neg, Num(1), Add]);
+        assert_eq!(ops("-d6"), vec![Num(1), Num(6), D, Neg]);
+        assert_eq!(ops("1d-3..3"), vec![Num(1), Num(-3), Num(3), Range, D]);
+        assert_eq!(ops("-(2 + 3)"), vec![Num(2), Num(3), Add, Neg]);
+        assert_eq!(ops("- -3"), vec![Num(-3), Neg]);
+        assert_eq!(ops("2 - -3"), vec![Num(2), Num(-3), Sub]);
+        assert_eq!(ops("-$x"), vec![Word("x".to_string()), Var, Neg]);
+    }
+
+    #[test]
+    pub fn test_program() {
+        let p = parse_program("# stats\n3d6 as a; 2\n\n(1\n+ 2) # sum\n").unwrap();
+        let p: Vec<Vec<Operation>> = p.into_iter().map(|e| e.ops).collect();
+        assert_eq!(
+            p,
+            vec![
+                vec![Num(3), Num(6), D, Word("a".to_string()), As],
+                vec![Num(2)],
+                vec![Num(1), Num(2), Add],
+            ]
+        );
+        assert!(parse_expr("1; 2").is_err());
+        assert!(parse_expr("1)").is_err());
+        assert_eq!(ops("1 + 2\n"), vec![Num(1), Num(2), Add]);
+        assert!(parse_program("1 2").is_err());
+        assert!(parse_program("").unwrap().is_empty());
+    }
+
+    fn error_span(s: &str) -> (String, Option<Span>) {
+        let e = parse_expr(s).unwrap_err();
+        let d = e.downcast::<DiceError>().unwrap();
+        (d.kind.to_string(), d.span)
+    }
+
+    #[test]
+    pub fn test_spans() {
+        let e = parse_expr("3d6 + $x").unwrap();
+        assert_eq!(
+            e.spans,
+            vec![
+                Span::new(0, 1),
+                Span::new(2, 3),
+                Span::new(1, 2),
+                Span::new(7, 8),
+                Span::new(6, 8),
+                Span::new(4, 5)
+            ]
+        );
+        assert_eq!(
+            error_span("1d6 + + 2"),
+            (
+                "Expected a value, found '+'".to_string(),
+                Some(Span::new(6, 7))
+            )
+        );
+        assert_eq!(
+            error_span("(1 + 2"),
+            (
+                "Expected ')', found the end of input".to_string(),
+                Some(Span::new(6, 6))
+            )
+        );
+        assert_eq!(error_span("2 ?").1, Some(Span::new(2, 3)));
+    }
+
+    #[test]
+    pub fn test_nesting() {
+        let nest = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse_expr(&nest(255)).is_ok());
+        let (msg, sp) = error_span(&nest(256));
+        assert_eq!(msg, "Limit exceeded: more than 256 levels of nesting");
+        assert_eq!(sp, Some(Span::new(256, 257)));
+        assert!(parse_expr(&nest(20_000)).is_err());
+        assert!(parse_expr(&format!("{}1", "-".repeat(50_000))).is_err());
+        assert!(parse_program(&format!("{}1", "$".repeat(50_000))).is_err());
+        assert!(parse_expr(&format!("{}1", "1 + ".repeat(255))).is_ok());
+        assert!(parse_expr(&format!("{}1", "1 + ".repeat(50_000))).is_err());
+    }
+}
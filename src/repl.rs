@@ -0,0 +1,164 @@
+use dice_lang::context::Context;
+use dice_lang::{error, parser};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+Enter expressions separated by ';' to roll them, variables set with 'as' are kept between lines
+unless set inside a 'let' or a '{ }' block.
+End a line with '\\' or leave a bracket open to continue it on the next line.
+  :vars        show variables
+  :rolls       show every roll so far
+  :clear       forget variables, rolls and the stack
+  :seed <n>    make the following rolls reproducible
+  :help        show this message
+  :quit        leave";
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".dice_lang_history"))
+}
+
+/// True if the input has brackets or quotes still waiting to close.
+/// Anything after a '#' on a line is a comment.
+fn unclosed(s: &str) -> bool {
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut comment = false;
+    for c in s.chars() {
+        match c {
+            '\n' => comment = false,
+            _ if comment => {}
+            '"' => quoted = !quoted,
+            '#' if !quoted => comment = true,
+            '(' | '[' | '{' if !quoted => depth += 1,
+            ')' | ']' | '}' if !quoted => depth -= 1,
+            _ => {}
+        }
+    }
+    quoted || depth > 0
+}
+
+/// Run a meta command, returning false if the repl should stop
+fn meta(cmd: &str, ct: &mut Context) -> bool {
+    let mut parts = cmd.split_whitespace();
+    match parts.next().unwrap_or("") {
+        ":vars" => {
+            for (k, v) in ct.vars() {
+                if !k.starts_with('_') {
+                    println!("{} = {}", k, v);
+                }
+            }
+        }
+        ":rolls" => {
+            for r in ct.rolls() {
+                println!("{}", r);
+            }
+        }
+        ":clear" => ct.clear(),
+        ":seed" => match parts.next().map(str::parse) {
+            Some(Ok(n)) => ct.reseed(n),
+            _ => println!(":seed needs a number"),
+        },
+        ":help" => println!("{}", HELP),
+        ":quit" | ":q" => return false,
+        c => println!("Unknown command '{}', try :help", c),
+    }
+    true
+}
+
+fn eval(s: &str, ct: &mut Context) -> anyhow::Result<()> {
+    let run = |ct: &mut Context| -> anyhow::Result<()> {
+        for e in parser::parse_program(s)? {
+            let (_, ex) = e.explain(ct)?;
+            println!("{}", ex);
+        }
+        Ok(())
+    };
+    run(ct).map_err(|e| error::in_source(e, s))
+}
+
+/// Read and roll expressions line by line, keeping one Context throughout
+pub fn run(ct: &mut Context) -> anyhow::Result<()> {
+    let mut rl = DefaultEditor::new()?;
+    let history = history_file();
+    if let Some(h) = &history {
+        // no history yet is fine
+        let _ = rl.load_history(h);
+    }
+
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        let line = match rl.readline(prompt) {
+            Ok(l) => l,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        match line.strip_suffix('\\') {
+            Some(l) => {
+                input.push_str(l);
+                input.push(' ');
+                continue;
+            }
+            None => input.push_str(&line),
+        }
+        if unclosed(&input) {
+            input.push('\n');
+            continue;
+        }
+
+        let s = std::mem::take(&mut input);
+        let s = s.trim();
+        if s.is_empty() {
+            continue;
+        }
+        rl.add_history_entry(s)?;
+        if s.starts_with(':') {
+            if !meta(s, ct) {
+                break;
+            }
+            continue;
+        }
+        if let Err(e) = eval(s, ct) {
+            println!("Error : {}", e);
+        }
+    }
+
+    if let Some(h) = &history {
+        rl.save_history(h)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod repl_test {
+    use super::*;
+
+    #[test]
+    pub fn test_unclosed() {
+        assert!(unclosed("(1 + "));
+        assert!(unclosed("[1, 2,"));
+        assert!(unclosed("\"abc"));
+        assert!(!unclosed("(1 + 2) * [3]"));
+        assert!(!unclosed("\"(\""));
+        assert!(!unclosed("1d6 # see (PHB"));
+        assert!(unclosed("(1 # )\n+ 2"));
+        assert!(!unclosed("\"#\" + (1)"));
+    }
+
+    #[test]
+    pub fn test_persistent_vars() {
+        let mut ct = Context::seeded(1);
+        eval("4d6h3 as str", &mut ct).unwrap();
+        eval("$str + 2", &mut ct).unwrap();
+        assert!(ct.get_var("str").is_some());
+        meta(":clear", &mut ct);
+        assert!(ct.get_var("str").is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::context::Context;
+use crate::dice::Value;
+use crate::error;
+use crate::explain::Node;
+use crate::limits::Limits;
+use crate::parser::parse_program;
+use err_tools::*;
+use rand::RngCore;
+use std::fmt::{self, Display};
+
+/// The result of a roll, with how it was reached
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub value: Value,
+    pub explanation: Node,
+    /// Every die pool rolled along the way
+    pub rolls: Vec<Value>,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.explanation)
+    }
+}
+
+/// Roll a program with fresh state, returning the outcome of its last statement
+pub fn roll(s: &str) -> anyhow::Result<Outcome> {
+    Roller::new().roll(s)
+}
+
+/// Rolls programs one after another, keeping variables between them
+#[derive(Debug, Default)]
+pub struct Roller {
+    ct: Context,
+}
+
+impl Roller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A Roller whose rolls are reproducible from the seed
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            ct: Context::seeded(seed),
+        }
+    }
+
+    pub fn with_rng<R: RngCore + 'static>(rng: R) -> Self {
+        Self {
+            ct: Context::with_rng(rng),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.ct.set_limits(limits);
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.ct
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.ct
+    }
+
+    /// Roll each statement of the program, returning the outcome of the last.
+    /// Errors show where in the program they happened.
+    pub fn roll(&mut self, s: &str) -> anyhow::Result<Outcome> {
+        self.roll_program(s).map_err(|e| error::in_source(e, s))
+    }
+
+    fn roll_program(&mut self, s: &str) -> anyhow::Result<Outcome> {
+        let first = self.ct.rolls().len();
+        let mut res = None;
+        for e in parse_program(s)? {
+            res = Some(e.explain(&mut self.ct)?);
+        }
+        let (value, explanation) = res.e_str("Nothing to roll")?;
+        Ok(Outcome {
+            value,
+            explanation,
+            rolls: self.ct.rolls()[first..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod roller_test {
+    use super::*;
+
+    #[test]
+    pub fn test_roll() {
+        let o = roll("2d[3] + 1").unwrap();
+        assert_eq!(o.value, Value::Num(7));
+        assert_eq!(o.to_string(), "[3, 3] = 6 + 1 = 7");
+        assert_eq!(o.rolls.len(), 1);
+
+        let mut r = Roller::seeded(4);
+        r.roll("2d[5] as x").unwrap();
+        let o = r.roll("$x + 1").unwrap();
+        assert_eq!(o.value, Value::Num(11));
+        assert!(o.rolls.is_empty());
+        assert_eq!(
+            Roller::seeded(9).roll("10d20").unwrap(),
+            Roller::seeded(9).roll("10d20").unwrap()
+        );
+
+        let e = r.roll("1 +").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Expected a value, found the end of input\n   1 | 1 +\n     |    ^"
+        );
+        assert!(r.roll("# nothing").is_err());
+
+        // failed rolls leave nothing behind to count against later ones
+        let mut r = Roller::new();
+        for _ in 0..1001 {
+            assert!(r.roll("1 + $nope").is_err());
+        }
+        assert_eq!(r.roll("2 + 2").unwrap().value, Value::Num(4));
+    }
+}
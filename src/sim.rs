@@ -0,0 +1,166 @@
+use crate::context::Context;
+use crate::expr::Expr;
+use err_tools::*;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::thread;
+
+/// Runs are split into chunks of this size, each with its own seed,
+/// so the results do not depend on how many threads did the work
+pub const CHUNK_SIZE: usize = 10_000;
+
+/// Aggregated results of running an expression many times
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub histogram: BTreeMap<i32, usize>,
+    pub runs: usize,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            histogram: BTreeMap::new(),
+            runs: 0,
+        }
+    }
+
+    fn add(&mut self, v: i32) {
+        *self.histogram.entry(v).or_insert(0) += 1;
+        self.runs += 1;
+    }
+
+    fn merge(&mut self, b: Stats) {
+        for (v, n) in b.histogram {
+            *self.histogram.entry(v).or_insert(0) += n;
+        }
+        self.runs += b.runs;
+    }
+
+    pub fn mean(&self) -> f64 {
+        let total: f64 = self
+            .histogram
+            .iter()
+            .map(|(v, n)| *v as f64 * *n as f64)
+            .sum();
+        total / self.runs as f64
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let total: f64 = self
+            .histogram
+            .iter()
+            .map(|(v, n)| (*v as f64 - mean).powi(2) * *n as f64)
+            .sum();
+        (total / self.runs as f64).sqrt()
+    }
+
+    /// The smallest result at least `p` (0..=1) of runs were less than or equal to
+    pub fn percentile(&self, p: f64) -> Option<i32> {
+        let target = (p * self.runs as f64).ceil().max(1.) as usize;
+        let mut seen = 0;
+        for (v, n) in &self.histogram {
+            seen += n;
+            if seen >= target {
+                return Some(*v);
+            }
+        }
+        None
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (v, n) in &self.histogram {
+            writeln!(f, "{} : {}", v, n)?;
+        }
+        write!(
+            f,
+            "Runs = {}, Mean = {:.4}, StdDev = {:.4}",
+            self.runs,
+            self.mean(),
+            self.std_dev()
+        )?;
+        for p in [0.05, 0.25, 0.5, 0.75, 0.95] {
+            if let Some(v) = self.percentile(p) {
+                write!(f, ", P{} = {}", (p * 100.) as i32, v)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run_chunk(e: &Expr, runs: usize, seed: u64) -> anyhow::Result<Stats> {
+    let mut res = Stats::new();
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..runs {
+        let mut ct = Context::seeded(rng.next_u64());
+        res.add(e.resolve(&mut ct)?.as_int()?);
+    }
+    Ok(res)
+}
+
+/// Run the expression `runs` times across all available threads, each run in a fresh Context.
+/// The same seed always gives the same Stats.
+pub fn simulate(e: &Expr, runs: usize, seed: u64) -> anyhow::Result<Stats> {
+    if runs == 0 {
+        return e_str("Simulation needs at least one run");
+    }
+    let mut master = StdRng::seed_from_u64(seed);
+    let chunks: Vec<(usize, u64)> = (0..runs)
+        .step_by(CHUNK_SIZE)
+        .map(|start| (CHUNK_SIZE.min(runs - start), master.next_u64()))
+        .collect();
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(chunks.len())
+        .max(1);
+
+    let results = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let chunks = &chunks;
+                s.spawn(move || {
+                    let mut res = Stats::new();
+                    for (runs, seed) in chunks.iter().skip(t).step_by(threads) {
+                        res.merge(run_chunk(e, *runs, *seed)?);
+                    }
+                    Ok(res)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().ok().e_str("Simulation thread panicked")?)
+            .collect::<Vec<anyhow::Result<Stats>>>()
+    });
+
+    let mut res = Stats::new();
+    for r in results {
+        res.merge(r?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod sim_test {
+    use super::*;
+    use crate::parser::parse_expr;
+
+    #[test]
+    pub fn test_simulate() {
+        let e = parse_expr("2d6").unwrap();
+        let a = simulate(&e, 25_000, 7).unwrap();
+        let b = simulate(&e, 25_000, 7).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.runs, 25_000);
+        assert!((a.mean() - 7.).abs() < 0.1);
+        assert_eq!(a.percentile(0.), Some(2));
+        assert_eq!(a.percentile(1.), Some(12));
+        assert_eq!(a.percentile(0.5), Some(7));
+        assert!(simulate(&e, 0, 7).is_err());
+    }
+}
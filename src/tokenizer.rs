@@ -1,4 +1,5 @@
-use err_tools::*;
+use crate::dice::{Compare, Round};
+use crate::error::{DiceError, ErrorKind, Span};
 use std::str::CharIndices;
 
 pub type TokenRes<'a> = anyhow::Result<Option<Token<'a>>>;
@@ -6,22 +7,31 @@ pub type TokenRes<'a> = anyhow::Result<Option<Token<'a>>>;
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType<'a> {
     D,
+    Z,
+    Percentile,
     L,
     H,
     P,
     F,
-    Number(i32),
+    Number(i64), //Up to 2^31, which only fits an i32 once negated
     Word(&'a str),
     Equal,
     Greater,
     Less,
+    GreaterEq,
+    LessEq,
     ParenO,
     ParenC,
     BraceO,
     BraceC,
+    CurlyO,
+    CurlyC,
     Dollar,
     Sub,
     Add,
+    Mul,
+    Div(Round),
+    Mod,
     Append,
     Push,
     Pop,
@@ -32,12 +42,35 @@ pub enum TokenType<'a> {
     As,
     HighestN,
     LowestN,
+    Explode,
+    Compound,
+    Penetrate,
+    RerollOnce,
+    Reroll,
+    Successes,
+    Double,
+    Fail,
+    Semi,
+    Fn,
+    Assign,
+    Let,
+    In,
+    Bool(bool),
+    Is(Compare), //Comparisons giving true or false, rather than filtering
+    And,
+    Or,
+    Not,
+    If,
+    Then,
+    Else,
+    Repeat,
 }
 
 impl<'a> TokenType<'a> {
     pub fn from_word(s: &'a str) -> Self {
         match s {
             "D" | "d" => TokenType::D,
+            "z" => TokenType::Z,
             "push" => TokenType::Push,
             "pop" => TokenType::Pop,
             "P" => TokenType::P,
@@ -49,40 +82,111 @@ impl<'a> TokenType<'a> {
             "k" => TokenType::LowestN,
             "h" => TokenType::HighestN,
             "K" => TokenType::HighestN,
+            "e" => TokenType::Explode,
+            "p" => TokenType::Penetrate,
+            "r" | "ro" => TokenType::RerollOnce,
+            "rr" => TokenType::Reroll,
+            "s" => TokenType::Successes,
+            "dbl" => TokenType::Double,
+            "f" => TokenType::Fail,
+            "fn" => TokenType::Fn,
+            "let" => TokenType::Let,
+            "in" => TokenType::In,
+            "true" => TokenType::Bool(true),
+            "false" => TokenType::Bool(false),
+            "eq" => TokenType::Is(Compare::Equal),
+            "ne" => TokenType::Is(Compare::NotEqual),
+            "lt" => TokenType::Is(Compare::Less),
+            "le" => TokenType::Is(Compare::LessEq),
+            "gt" => TokenType::Is(Compare::Greater),
+            "ge" => TokenType::Is(Compare::GreaterEq),
+            "and" => TokenType::And,
+            "or" => TokenType::Or,
+            "not" => TokenType::Not,
+            "if" => TokenType::If,
+            "then" => TokenType::Then,
+            "else" => TokenType::Else,
+            "repeat" => TokenType::Repeat,
 
             s => TokenType::Word(s),
         }
     }
 
+    /// Modifier letters like 'e' or 'f' only act as modifiers after a roll,
+    /// so they still work as variable and function names
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Explode
+                | TokenType::Penetrate
+                | TokenType::RerollOnce
+                | TokenType::Reroll
+                | TokenType::Successes
+                | TokenType::Double
+                | TokenType::Fail
+        )
+    }
+
     pub fn precedence(&self) -> i32 {
         match self {
             Self::Comma => -1,
+            Self::Semi => -1,
+            Self::Assign => -1,
+            Self::In => -1,
+            Self::Then => -1,
+            Self::Else => -1,
+            Self::Fn => 1,
+            Self::Let => 1,
+            Self::If => 1,
             Self::ParenC => -1,
             Self::BraceC => -1,
+            Self::CurlyC => -1,
             Self::Colon => 1,
             Self::As => 1,
             Self::Count => 1,
             Self::Equal => 1,
             Self::Greater => 1,
             Self::Less => 1,
+            Self::GreaterEq => 1,
+            Self::LessEq => 1,
+            Self::Explode => 1,
+            Self::Compound => 1,
+            Self::Penetrate => 1,
+            Self::RerollOnce => 1,
+            Self::Reroll => 1,
+            Self::Double => 1,
+            Self::Fail => 1,
             Self::L => 1,
             Self::H => 1,
             Self::P => 1,
             Self::F => 1,
             Self::Number(_) => 1,
             Self::Word(_) => 1,
-            Self::HighestN => 2,
-            Self::LowestN => 2,
-            Self::Pop => 2,
-            Self::Push => 3,
-            Self::Add => 4,
-            Self::Append => 4,
-            Self::Sub => 5,
-            Self::D => 9,
-            Self::Range => 10,
-            Self::ParenO => 11,
-            Self::BraceO => 11,
-            Self::Dollar => 12,
+            Self::Bool(_) => 1,
+            Self::Or => 2,
+            Self::And => 3,
+            Self::Not => 3,
+            Self::Repeat => 3,
+            Self::Is(_) => 4,
+            Self::Pop => 5,
+            Self::Push => 6,
+            Self::Add => 7,
+            Self::Append => 7,
+            Self::Sub => 8,
+            Self::Mul => 9,
+            Self::Div(_) => 9,
+            Self::Mod => 9,
+            Self::HighestN => 10,
+            Self::LowestN => 10,
+            Self::Successes => 10,
+            Self::D => 12,
+            Self::Z => 12,
+            Self::Percentile => 12,
+            Self::Range => 13,
+            Self::ParenO => 14,
+            Self::BraceO => 14,
+            Self::CurlyO => 14,
+            Self::Dollar => 15,
         }
     }
 }
@@ -95,6 +199,21 @@ pub struct Token<'a> {
     pub end: usize,
 }
 
+impl<'a> Token<'a> {
+    pub fn span(&self) -> Span {
+        Span::new(self.start, self.end)
+    }
+
+    /// The name this token spells where a value or name is expected
+    pub fn name(&self) -> Option<&'a str> {
+        match &self.tt {
+            TokenType::Word(w) => Some(*w),
+            tt if tt.is_modifier() => Some(self.s),
+            _ => None,
+        }
+    }
+}
+
 pub fn print_tokens(s: &str) {
     let mut t = Tokenizer::new(s);
     while let Ok(Some(t)) = t.next() {
@@ -107,15 +226,17 @@ pub struct Tokenizer<'a> {
     chars: CharIndices<'a>,
     start: usize,
     peek: Option<(usize, char)>,
+    open: Vec<char>, //Open brackets, newlines only end statements outside '(' and '['
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn new(s: &'a str) -> Self {
         Self {
-            s: s,
+            s,
             chars: s.char_indices(),
             start: 0,
             peek: None,
+            open: Vec::new(),
         }
     }
 
@@ -158,22 +279,36 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// An error covering the text from the start of the current token
+    pub fn error(&mut self, msg: &str) -> TokenRes<'a> {
+        self.error_kind(ErrorKind::Message(msg.to_string()))
+    }
+
+    pub fn error_kind(&mut self, kind: ErrorKind) -> TokenRes<'a> {
+        let end = self.peek_index().max(self.start + 1);
+        Err(DiceError::new(kind, Some(Span::new(self.start, end))).into())
+    }
+
     pub fn number(&mut self) -> TokenRes<'a> {
-        let mut res: i32 = 0;
+        let max = -(i32::MIN as i64);
+        let mut res = Some(0i64);
         let mut found = false;
         loop {
             match self.peek_char() {
-                Some((_, n)) if n >= '0' && n <= '9' => {
-                    res = res * 10 + (n as i32 - '0' as i32);
+                Some((_, n)) if n.is_ascii_digit() => {
+                    let d = n as i64 - '0' as i64;
+                    res = res
+                        .and_then(|r| r.checked_mul(10)?.checked_add(d))
+                        .filter(|r| *r <= max);
                     found = true;
                     self.peek = None;
                 }
                 _ => {
-                    if found {
-                        return self.make_token_wrap(TokenType::Number(res), false);
-                    } else {
-                        return e_str("No Number Digits found in number method");
-                    }
+                    return match (found, res) {
+                        (true, Some(n)) => self.make_token_wrap(TokenType::Number(n), false),
+                        (true, None) => self.error_kind(ErrorKind::Overflow),
+                        _ => self.error("No Number Digits found in number method"),
+                    };
                 }
             }
         }
@@ -184,6 +319,9 @@ impl<'a> Tokenizer<'a> {
         loop {
             match self.peek_char() {
                 Some((_, c)) if c.is_alphabetic() || c == '_' => self.peek = None,
+                Some((i, '%')) if matches!(&self.s[start..i], "d" | "D") => {
+                    return self.make_token_wrap(TokenType::Percentile, true)
+                }
                 Some((i, _)) => {
                     return self.make_token_wrap(TokenType::from_word(&self.s[start..i]), false)
                 }
@@ -201,27 +339,36 @@ impl<'a> Tokenizer<'a> {
                     return self.make_token_wrap(TokenType::Word(&self.s[start..end]), false);
                 }
                 Some(_) => {}
-                None => return e_str("EOI inside quotes"),
+                None => return self.error("EOI inside quotes"),
             }
         }
     }
 
+    /// Skip whitespace and '#' comments, stopping at newlines that could end a statement
     pub fn white_space(&mut self) {
         loop {
             match self.peek_char() {
+                Some((_, '\n')) if !matches!(self.open.last(), Some('(' | '[')) => return,
                 Some((_, c)) if c.is_whitespace() => {
                     self.peek = None;
                 }
+                Some((_, '#')) => loop {
+                    match self.peek_char() {
+                        Some((_, '\n')) | None => break,
+                        _ => self.peek = None,
+                    }
+                },
                 _ => return,
             }
         }
     }
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> TokenRes<'a> {
         let follow = |s: &mut Self, c: char, tt: TokenType<'a>| {
             s.peek = None;
             match s.next_char() {
                 Some((_, r)) if r == c => s.make_token_wrap(tt, false),
-                _ => e_str("Expected second Dot"),
+                _ => s.error(&format!("Expected '{}' after '{}'", c, c)),
             }
         };
         let follow_def = |s: &mut Self, c: char, tt: TokenType<'a>, def: TokenType<'a>| {
@@ -238,25 +385,53 @@ impl<'a> Tokenizer<'a> {
             Some(v) => v,
         };
         match pc.1 {
-            c if c >= '0' && c <= '9' => self.number(),
+            c if c.is_ascii_digit() => self.number(),
             '\"' => self.qoth(),
-            '(' => self.make_token_wrap(TokenType::ParenO, true),
-            ')' => self.make_token_wrap(TokenType::ParenC, true),
-            '[' => self.make_token_wrap(TokenType::BraceO, true),
-            ']' => self.make_token_wrap(TokenType::BraceC, true),
+            '(' | '[' | '{' => {
+                self.open.push(pc.1);
+                let tt = match pc.1 {
+                    '(' => TokenType::ParenO,
+                    '[' => TokenType::BraceO,
+                    _ => TokenType::CurlyO,
+                };
+                self.make_token_wrap(tt, true)
+            }
+            ')' | ']' | '}' => {
+                self.open.pop();
+                let tt = match pc.1 {
+                    ')' => TokenType::ParenC,
+                    ']' => TokenType::BraceC,
+                    _ => TokenType::CurlyC,
+                };
+                self.make_token_wrap(tt, true)
+            }
+            ';' | '\n' => self.make_token_wrap(TokenType::Semi, true),
             '+' => follow_def(self, '+', TokenType::Append, TokenType::Add),
             '-' => self.make_token_wrap(TokenType::Sub, true),
+            '*' => self.make_token_wrap(TokenType::Mul, true),
+            '%' => self.make_token_wrap(TokenType::Mod, true),
+            '/' => {
+                self.peek = None;
+                match self.peek_char() {
+                    Some((_, '^')) => self.make_token_wrap(TokenType::Div(Round::Up), true),
+                    Some((_, '~')) => self.make_token_wrap(TokenType::Div(Round::Nearest), true),
+                    _ => self.make_token_wrap(TokenType::Div(Round::Down), false),
+                }
+            }
             '$' => self.make_token_wrap(TokenType::Dollar, true),
             ':' => self.make_token_wrap(TokenType::Colon, true),
             ',' => self.make_token_wrap(TokenType::Comma, true),
             '.' => follow(self, '.', TokenType::Range),
-            '=' => follow(self, '=', TokenType::Equal),
-            '<' => self.make_token_wrap(TokenType::Less, true),
-            '>' => self.make_token_wrap(TokenType::Greater, true),
-            '!' => self.make_token_wrap(TokenType::Count, true),
+            '=' => follow_def(self, '=', TokenType::Equal, TokenType::Assign),
+            '<' => follow_def(self, '=', TokenType::LessEq, TokenType::Less),
+            '>' => follow_def(self, '=', TokenType::GreaterEq, TokenType::Greater),
+            '!' => follow_def(self, '!', TokenType::Compound, TokenType::Count),
             c if c.is_alphabetic() || c == '_' => self.unqoth(),
 
-            _ => e_str("Unexpected Character"),
+            c => {
+                self.peek = None;
+                self.error(&format!("Unexpected Character '{}'", c))
+            }
         }
     }
 }
@@ -282,6 +457,53 @@ mod token_test {
         assert_eq!(t.tt, TokenType::Word("food"));
         t = tk.next().unwrap().unwrap();
         assert_eq!(t.tt, TokenType::Number(3));
-        assert!(tk.next().is_none());
+        assert!(tk.next().unwrap().is_none());
+    }
+
+    #[test]
+    pub fn test_number_overflow() {
+        let mut tk = Tokenizer::new("2147483648 2147483649");
+        assert_eq!(
+            tk.next().unwrap().unwrap().tt,
+            TokenType::Number(2147483648)
+        );
+        let e = tk.next().unwrap_err().downcast::<DiceError>().unwrap();
+        assert_eq!(e.kind, ErrorKind::Overflow);
+        assert_eq!(e.span, Some(Span::new(11, 21)));
+    }
+
+    #[test]
+    pub fn test_statements() {
+        let s = "1 # one\n(2\n+ 3); 4 #four";
+        let mut tk = Tokenizer::new(s);
+        let mut tts = Vec::new();
+        while let Some(t) = tk.next().unwrap() {
+            tts.push(t.tt);
+        }
+        use TokenType::*;
+        assert_eq!(
+            tts,
+            vec![
+                Number(1),
+                Semi,
+                ParenO,
+                Number(2),
+                Add,
+                Number(3),
+                ParenC,
+                Semi,
+                Number(4)
+            ]
+        );
+
+        let mut tk = Tokenizer::new("{1\n(2\n)}");
+        let mut tts = Vec::new();
+        while let Some(t) = tk.next().unwrap() {
+            tts.push(t.tt);
+        }
+        assert_eq!(
+            tts,
+            vec![CurlyO, Number(1), Semi, ParenO, Number(2), ParenC, CurlyC]
+        );
     }
 }